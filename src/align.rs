@@ -0,0 +1,322 @@
+//! Finding the offset between two recordings of the same sound.
+//!
+//! The audio of the clips is cross-correlated, and the lag with the strongest
+//! correlation is where the sounds line up. Comparing that with the difference
+//! between the creation dates of the clips gives the difference between the
+//! clocks of the cameras that recorded them.
+
+use std::f64::consts::PI;
+use std::ops::{Add, Mul, Sub};
+
+use time::Duration;
+
+use crate::audio::{Audio, Window, MAX_DURATION, SAMPLE_RATE};
+
+/// Correlations below this are unlikely to be the same sound.
+pub const MIN_CORRELATION: f64 = 0.3;
+
+/// How far out the clock of a camera can be for its clips to be found where
+/// they overlap with the reference by their creation dates.
+pub const MAX_CLOCK_ERROR: Duration = Duration::minutes(5);
+
+/// The clips must overlap by at least this many seconds.
+const MIN_OVERLAP: u32 = 2;
+
+/// Where the audio of one clip lines up with another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    /// How long after the start of the first clip the second one starts, negative
+    /// if it starts before
+    pub lag: Duration,
+    /// The correlation of the clips' audio where they overlap at that lag, from -1
+    /// to 1
+    pub correlation: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+/// The windows of a reference clip and of a clip that starts `dated_lag` after
+/// it by their creation dates to decode, so that they cover where the clips
+/// overlap.
+///
+/// The window of the reference is [`MAX_CLOCK_ERROR`] wider on each side, to
+/// allow for the clocks of the cameras being out.
+pub fn overlap_windows(dated_lag: Duration) -> (Window, Window) {
+    let overlap_start = dated_lag.max(Duration::ZERO);
+    let reference_start = (overlap_start - MAX_CLOCK_ERROR).max(Duration::ZERO);
+    let reference = Window {
+        start: reference_start,
+        length: overlap_start - reference_start + MAX_DURATION + MAX_CLOCK_ERROR,
+    };
+    let clip = Window {
+        start: (-dated_lag).max(Duration::ZERO),
+        length: MAX_DURATION,
+    };
+    (reference, clip)
+}
+
+/// Find where the audio of clip `b` lines up with the audio of clip `a`, or
+/// `None` if they can't overlap by long enough.
+///
+/// This is the lag with the strongest correlation, which isn't necessarily a
+/// good match. Check [`Alignment::correlation`] against [`MIN_CORRELATION`].
+pub fn align(a: &Audio, b: &Audio) -> Option<Alignment> {
+    // The lag between the starts of the decoded windows, not the clips
+    let window_lag = a.start() - b.start();
+    let a = remove_mean(a.samples());
+    let b = remove_mean(b.samples());
+    let min_overlap = (MIN_OVERLAP * SAMPLE_RATE) as usize;
+    if a.len() < min_overlap || b.len() < min_overlap {
+        return None;
+    }
+
+    let correlation = cross_correlate(&a, &b);
+    // The lag of a correlation by its index, with negative lags wrapped around
+    let len = correlation.len() as isize;
+    let lag = |i: usize| {
+        let i = i as isize;
+        if i < a.len() as isize {
+            i
+        } else {
+            i - len
+        }
+    };
+    // The samples of each clip that overlap at a lag
+    let overlap = |lag: isize| {
+        let a_start = lag.max(0) as usize;
+        let b_start = (-lag).max(0) as usize;
+        let overlap = a
+            .len()
+            .saturating_sub(a_start)
+            .min(b.len().saturating_sub(b_start));
+        (a_start..a_start + overlap, b_start..b_start + overlap)
+    };
+
+    let (best, &peak) = correlation
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| overlap(lag(i)).0.len() >= min_overlap)
+        .max_by(|(_, x), (_, y)| x.total_cmp(y))?;
+
+    // Estimate the lag between samples from a parabola through the peak
+    let before = correlation[(best + correlation.len() - 1) % correlation.len()];
+    let after = correlation[(best + 1) % correlation.len()];
+    let curvature = before - 2. * peak + after;
+    let fraction = if curvature < 0. {
+        0.5 * (before - after) / curvature
+    } else {
+        0.
+    };
+
+    let (a_range, b_range) = overlap(lag(best));
+    let energy = |x: &[f64]| x.iter().map(|x| x * x).sum::<f64>();
+    let energy = (energy(&a[a_range]) * energy(&b[b_range])).sqrt();
+    Some(Alignment {
+        lag: Duration::seconds_f64((lag(best) as f64 + fraction) / f64::from(SAMPLE_RATE))
+            + window_lag,
+        correlation: if energy > 0. { peak / energy } else { 0. },
+    })
+}
+
+fn remove_mean(samples: &[f32]) -> Vec<f64> {
+    let mean = samples.iter().map(|&x| f64::from(x)).sum::<f64>() / samples.len().max(1) as f64;
+    samples.iter().map(|&x| f64::from(x) - mean).collect()
+}
+
+/// The cross-correlation of `a` and `b`, where the value at index `i` is the sum
+/// of `a[n + i] * b[n]`. Negative lags wrap around to the end.
+fn cross_correlate(a: &[f64], b: &[f64]) -> Vec<f64> {
+    let len = (a.len() + b.len() - 1).next_power_of_two();
+    let spectrum = |x: &[f64]| {
+        let mut buf: Vec<_> = x.iter().map(|&re| Complex { re, im: 0. }).collect();
+        buf.resize(len, Complex { re: 0., im: 0. });
+        fft(&mut buf, false);
+        buf
+    };
+    let mut product = spectrum(a);
+    for (x, y) in product.iter_mut().zip(spectrum(b)) {
+        *x = *x * y.conj();
+    }
+    fft(&mut product, true);
+    product.iter().map(|x| x.re / len as f64).collect()
+}
+
+/// An in place radix-2 fast Fourier transform, without scaling the inverse. The
+/// length must be a power of two.
+fn fft(buf: &mut [Complex], inverse: bool) {
+    let len = buf.len();
+    if len <= 1 {
+        return;
+    }
+
+    // Bit reversal permutation
+    let bits = len.trailing_zeros();
+    for i in 0..len {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            buf.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1. } else { -1. };
+    let mut size = 2;
+    while size <= len {
+        let angle = sign * 2. * PI / size as f64;
+        let step = Complex {
+            re: angle.cos(),
+            im: angle.sin(),
+        };
+        for start in (0..len).step_by(size) {
+            let mut twiddle = Complex { re: 1., im: 0. };
+            for i in start..start + size / 2 {
+                let even = buf[i];
+                let odd = buf[i + size / 2] * twiddle;
+                buf[i] = even + odd;
+                buf[i + size / 2] = even - odd;
+                twiddle = twiddle * step;
+            }
+        }
+        size *= 2;
+    }
+}
+
+impl Complex {
+    fn conj(self) -> Complex {
+        Complex {
+            re: self.re,
+            im: -self.im,
+        }
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re - other.re,
+            im: self.im - other.im,
+        }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pseudo-random noise
+    fn noise(len: usize, mut seed: u32) -> Vec<f32> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_cross_correlate() {
+        let correlation = cross_correlate(&[1., 2., 3.], &[1., 1.]);
+        let expected = [3., 5., 3., 1.];
+        assert_eq!(correlation.len(), expected.len());
+        for (x, y) in correlation.iter().zip(expected) {
+            assert!((x - y).abs() < 1e-9, "{:?}", correlation);
+        }
+    }
+
+    #[test]
+    fn test_align() {
+        let rate = SAMPLE_RATE as usize;
+        let sound = noise(20 * rate, 1);
+        let hiss = noise(20 * rate, 2);
+        // The second clip starts 2.5 seconds in, with some hiss of its own
+        let b: Vec<_> = sound[5 * rate / 2..]
+            .iter()
+            .zip(&hiss)
+            .map(|(x, y)| x + 0.2 * y)
+            .collect();
+        let a = Audio::from_samples(sound[..15 * rate].to_vec(), Duration::ZERO);
+        let b = Audio::from_samples(b, Duration::ZERO);
+
+        let close = |lag: Duration, expected| (lag - Duration::milliseconds(expected)).abs();
+        let alignment = align(&a, &b).unwrap();
+        assert!(
+            close(alignment.lag, 2500) < Duration::MILLISECOND,
+            "{:?}",
+            alignment
+        );
+        assert!(alignment.correlation > 0.9, "{:?}", alignment);
+
+        let alignment = align(&b, &a).unwrap();
+        assert!(
+            close(alignment.lag, -2500) < Duration::MILLISECOND,
+            "{:?}",
+            alignment
+        );
+
+        // Decoded from ten minutes into the first clip, and a minute into the
+        // second
+        let a = Audio::from_samples(a.samples().to_vec(), Duration::minutes(10));
+        let b = Audio::from_samples(b.samples().to_vec(), Duration::minutes(1));
+        let alignment = align(&a, &b).unwrap();
+        assert!(
+            close(alignment.lag, 9 * 60_000 + 2500) < Duration::MILLISECOND,
+            "{:?}",
+            alignment
+        );
+
+        let other = Audio::from_samples(noise(10 * rate, 3), Duration::ZERO);
+        assert!(align(&a, &other).unwrap().correlation < MIN_CORRELATION);
+        let short = Audio::from_samples(noise(rate, 3), Duration::ZERO);
+        assert_eq!(align(&a, &short), None);
+    }
+
+    #[test]
+    fn test_overlap_windows() {
+        let window = |start, length| Window {
+            start: Duration::minutes(start),
+            length: Duration::minutes(length),
+        };
+        // The clip starts 20 minutes into the reference
+        assert_eq!(
+            overlap_windows(Duration::minutes(20)),
+            (window(15, 15), window(0, 5))
+        );
+        // Only just into it
+        assert_eq!(
+            overlap_windows(Duration::minutes(2)),
+            (window(0, 12), window(0, 5))
+        );
+        // Or the reference starts 20 minutes into the clip
+        assert_eq!(
+            overlap_windows(Duration::minutes(-20)),
+            (window(0, 10), window(20, 5))
+        );
+    }
+}
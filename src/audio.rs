@@ -0,0 +1,956 @@
+//! Decoding the audio of video files, to line up clips of the same sound
+//! recorded by different cameras.
+//!
+//! Uncompressed (PCM) audio is decoded from MPEG4 and QuickTime `sowt`, `twos`,
+//! `raw `, `in24`, `in32`, `fl32`, `fl64` and `lpcm` tracks, and Matroska
+//! `A_PCM` tracks. AAC-LC audio, as most cameras record, is decoded with
+//! symphonia from MPEG4 `mp4a` tracks and Matroska `A_AAC` tracks. The channels
+//! are mixed down to mono, and the samples averaged down to [`SAMPLE_RATE`],
+//! which is plenty to match sounds on and keeps the matching quick.
+
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use matroska::{Matroska, Settings, Tracktype};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecParameters, Decoder as _, DecoderOptions, CODEC_TYPE_AAC};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::Packet;
+use symphonia::default::codecs::AacDecoder;
+use time::Duration;
+
+use crate::{bmff, ebml, Container, Error};
+
+/// The sample rate of decoded audio, in Hz.
+pub const SAMPLE_RATE: u32 = 2000;
+
+/// No more than this much of a clip is decoded.
+pub const MAX_DURATION: Duration = Duration::minutes(5);
+
+/// The audio of a clip, mixed down to mono at [`SAMPLE_RATE`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Audio {
+    samples: Vec<f32>,
+    /// How far into the clip the samples start
+    start: Duration,
+}
+
+/// The part of a clip to decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// How far into the clip to start
+    pub start: Duration,
+    /// How much to decode from there, at most
+    pub length: Duration,
+}
+
+/// How the samples of a PCM track are stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PcmFormat {
+    encoding: Encoding,
+    big_endian: bool,
+    /// Bytes per sample of one channel
+    bytes: usize,
+    channels: usize,
+    sample_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Unsigned,
+    Signed,
+    Float,
+}
+
+/// How the audio of a track is encoded.
+enum Codec {
+    Pcm(PcmFormat),
+    Aac(Box<AacDecoder>),
+}
+
+/// Mixes and averages samples down to mono at [`SAMPLE_RATE`] as they are decoded.
+struct Decoder {
+    codec: Codec,
+    /// The sample rate of the track, which for AAC is known once a packet has been
+    /// decoded
+    sample_rate: f64,
+    samples: Vec<f32>,
+    /// Bytes of a PCM frame split between two chunks or blocks
+    partial: Vec<u8>,
+    /// The number of frames decoded
+    frames: u64,
+    sum: f32,
+    count: u32,
+    /// The number of output samples passed, including those skipped
+    passed: usize,
+    /// The output samples before the window, which are skipped
+    skip: usize,
+    max_samples: usize,
+}
+
+impl Audio {
+    /// Decode the `window` of the audio of the file at `path`.
+    pub fn read_path(path: &Path, window: Window) -> Result<Audio, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let detection = Container::detect(&mut reader, path)?;
+        Audio::read(reader, detection.container, window)
+    }
+
+    /// Decode the `window` of the audio of the file in `reader`, of type
+    /// `container`.
+    pub fn read<R: Read + Seek>(
+        mut reader: R,
+        container: Container,
+        window: Window,
+    ) -> Result<Audio, Error> {
+        match container {
+            Container::Mp4 => read_mp4(&mut reader, window),
+            Container::Matroska => read_matroska(&mut reader, window),
+            Container::Jpeg | Container::Heif | Container::Tiff => Err(no_audio()),
+        }
+    }
+
+    pub(crate) fn from_samples(samples: Vec<f32>, start: Duration) -> Audio {
+        Audio { samples, start }
+    }
+
+    /// The decoded samples.
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    /// How far into the clip the decoded audio starts.
+    pub fn start(&self) -> Duration {
+        self.start
+    }
+
+    /// The duration of the decoded audio.
+    pub fn duration(&self) -> Duration {
+        Duration::seconds_f64(self.samples.len() as f64 / f64::from(SAMPLE_RATE))
+    }
+}
+
+impl Window {
+    /// The start of a clip, up to [`MAX_DURATION`] long.
+    pub const START: Window = Window {
+        start: Duration::ZERO,
+        length: MAX_DURATION,
+    };
+}
+
+impl PcmFormat {
+    fn frame_size(&self) -> usize {
+        self.bytes * self.channels
+    }
+
+    /// Decode one sample, scaled to between -1 and 1.
+    fn decode(&self, bytes: &[u8]) -> f32 {
+        let mut buf = [0; 8];
+        // Place the bytes at the most significant end of a little endian u64, so
+        // that integers of any size are scaled alike
+        let buf_end = &mut buf[8 - bytes.len()..];
+        buf_end.copy_from_slice(bytes);
+        if self.big_endian {
+            buf_end.reverse();
+        }
+        let value = u64::from_le_bytes(buf);
+        match self.encoding {
+            Encoding::Unsigned => ((value ^ (1 << 63)) as i64) as f32 / i64::MAX as f32,
+            Encoding::Signed => value as i64 as f32 / i64::MAX as f32,
+            Encoding::Float if self.bytes == 4 => f32::from_bits((value >> 32) as u32),
+            Encoding::Float => f64::from_bits(value) as f32,
+        }
+    }
+}
+
+impl Codec {
+    /// An AAC decoder for the AudioSpecificConfig in `config`.
+    fn aac(config: &[u8]) -> Result<Codec, Error> {
+        let mut params = CodecParameters::new();
+        params
+            .for_codec(CODEC_TYPE_AAC)
+            .with_extra_data(config.into());
+        AacDecoder::try_new(&params, &DecoderOptions::default())
+            .map(|decoder| Codec::Aac(Box::new(decoder)))
+            .map_err(aac_error)
+    }
+}
+
+impl Decoder {
+    fn new(codec: Codec, window: Window) -> Result<Decoder, Error> {
+        let sample_rate = match &codec {
+            Codec::Pcm(format) => {
+                let valid = match format.encoding {
+                    Encoding::Float => matches!(format.bytes, 4 | 8),
+                    Encoding::Signed | Encoding::Unsigned => (1..=8).contains(&format.bytes),
+                };
+                if !valid
+                    || format.channels == 0
+                    || format.sample_rate.is_nan()
+                    || format.sample_rate < 1.
+                {
+                    return Err(Error::UnsupportedAudio(String::from(
+                        "invalid PCM audio format",
+                    )));
+                }
+                format.sample_rate
+            }
+            Codec::Aac(_) => 0.,
+        };
+        Ok(Decoder {
+            codec,
+            sample_rate,
+            samples: Vec::new(),
+            partial: Vec::new(),
+            frames: 0,
+            sum: 0.,
+            count: 0,
+            passed: 0,
+            skip: samples_in(window.start),
+            max_samples: samples_in(window.length.min(MAX_DURATION)),
+        })
+    }
+
+    /// Whether enough audio has been decoded.
+    fn is_full(&self) -> bool {
+        self.samples.len() >= self.max_samples
+    }
+
+    /// Decode `data`, which is any run of bytes for PCM, and a single packet for
+    /// AAC.
+    fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        let decoder = match &mut self.codec {
+            Codec::Pcm(format) => {
+                let format = *format;
+                self.push_pcm(format, data);
+                return Ok(());
+            }
+            Codec::Aac(decoder) => decoder,
+        };
+        let decoded = match decoder.decode(&Packet::new_from_slice(0, 0, 0, data)) {
+            Ok(decoded) => decoded,
+            // Skip damaged packets, as players do
+            Err(SymphoniaError::DecodeError(_)) => return Ok(()),
+            Err(err) => return Err(aac_error(err)),
+        };
+        let spec = *decoded.spec();
+        let mut buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buf.copy_interleaved_ref(decoded);
+
+        self.sample_rate = f64::from(spec.rate);
+        let channels = spec.channels.count().max(1);
+        for frame in buf.samples().chunks_exact(channels) {
+            if self.is_full() {
+                break;
+            }
+            self.push_sample(frame.iter().sum::<f32>() / channels as f32);
+        }
+        Ok(())
+    }
+
+    fn push_pcm(&mut self, format: PcmFormat, mut data: &[u8]) {
+        let frame_size = format.frame_size();
+        if !self.partial.is_empty() {
+            let needed = (frame_size - self.partial.len()).min(data.len());
+            self.partial.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+            if self.partial.len() == frame_size {
+                let frame = std::mem::take(&mut self.partial);
+                self.push_frame(format, &frame);
+            }
+        }
+        let mut frames = data.chunks_exact(frame_size);
+        for frame in &mut frames {
+            if self.is_full() {
+                return;
+            }
+            self.push_frame(format, frame);
+        }
+        self.partial.extend_from_slice(frames.remainder());
+    }
+
+    fn push_frame(&mut self, format: PcmFormat, frame: &[u8]) {
+        let mono = frame
+            .chunks_exact(format.bytes)
+            .map(|sample| format.decode(sample))
+            .sum::<f32>()
+            / format.channels as f32;
+        self.push_sample(mono);
+    }
+
+    /// Add the next sample of the track, mixed down to mono.
+    fn push_sample(&mut self, mono: f32) {
+        // The output sample this frame falls in
+        let index = (self.frames as f64 * f64::from(SAMPLE_RATE) / self.sample_rate) as usize;
+        self.frames += 1;
+        if index > self.passed && self.count > 0 {
+            let average = self.sum / self.count as f32;
+            // Repeat samples if the audio has a lower rate than the output
+            while self.passed < index {
+                if self.passed >= self.skip {
+                    self.samples.push(average);
+                }
+                self.passed += 1;
+            }
+            self.sum = 0.;
+            self.count = 0;
+        }
+        self.sum += mono;
+        self.count += 1;
+    }
+
+    fn finish(mut self) -> Result<Audio, Error> {
+        if self.count > 0 && !self.is_full() && self.passed >= self.skip {
+            self.samples.push(self.sum / self.count as f32);
+        }
+        self.samples.truncate(self.max_samples);
+        if self.samples.is_empty() {
+            return Err(Error::UnsupportedAudio(String::from(if self.skip > 0 {
+                "the audio track ends before the part to be decoded"
+            } else {
+                "the audio track is empty"
+            })));
+        }
+        let start = Duration::seconds_f64(self.skip as f64 / f64::from(SAMPLE_RATE));
+        Ok(Audio::from_samples(self.samples, start))
+    }
+}
+
+/// The number of output samples in `duration`, or none if it's negative.
+fn samples_in(duration: Duration) -> usize {
+    (duration.as_seconds_f64() * f64::from(SAMPLE_RATE)).max(0.) as usize
+}
+
+fn no_audio() -> Error {
+    Error::UnsupportedAudio(String::from("no audio track"))
+}
+
+fn unsupported_codec(codec: &str) -> Error {
+    Error::UnsupportedAudio(format!(
+        "{} audio can't be decoded, only PCM and AAC",
+        codec
+    ))
+}
+
+fn aac_error(err: SymphoniaError) -> Error {
+    match err {
+        SymphoniaError::Unsupported(_) => Error::UnsupportedAudio(String::from(
+            "only AAC-LC audio with one or two channels can be decoded",
+        )),
+        err => Error::UnsupportedAudio(format!("invalid AAC audio: {}", err)),
+    }
+}
+
+/// Decode the first sound track of an MPEG4 or QuickTime file.
+fn read_mp4<R: Read + Seek>(reader: &mut R, window: Window) -> Result<Audio, Error> {
+    let moov = bmff::find_path(reader, &[b"moov"])?.ok_or_else(no_audio)?;
+    let mut offset = moov.start;
+    while let Some(trak) = bmff::find(reader, offset, moov.end, b"trak")? {
+        offset = trak.end;
+        let mdia = match bmff::find(reader, trak.start, trak.end, b"mdia")? {
+            Some(mdia) => mdia,
+            None => continue,
+        };
+        let hdlr = match bmff::find(reader, mdia.start, mdia.end, b"hdlr")? {
+            Some(hdlr) => hdlr,
+            None => continue,
+        };
+        // Version and flags, then the component type
+        reader.seek(SeekFrom::Start(hdlr.start + 8))?;
+        let mut handler = [0; 4];
+        reader.read_exact(&mut handler)?;
+        if &handler != b"soun" {
+            continue;
+        }
+        let minf = bmff::find(reader, mdia.start, mdia.end, b"minf")?;
+        let stbl = match minf {
+            Some(minf) => bmff::find(reader, minf.start, minf.end, b"stbl")?,
+            None => None,
+        };
+        if let Some(stbl) = stbl {
+            return read_sound_track(reader, stbl, window);
+        }
+    }
+    Err(no_audio())
+}
+
+fn read_sound_track<R: Read + Seek>(
+    reader: &mut R,
+    stbl: bmff::BoxHeader,
+    window: Window,
+) -> Result<Audio, Error> {
+    let invalid = || Error::UnsupportedAudio(String::from("invalid sound track"));
+    let table = |reader: &mut R, box_type| {
+        bmff::find(reader, stbl.start, stbl.end, box_type)?.ok_or_else(invalid)
+    };
+
+    // The first sample description, skipping the version, flags and entry count
+    let stsd = table(reader, b"stsd")?;
+    let entry = bmff::read_header(reader, stsd.start + 8, stsd.end)?.ok_or_else(invalid)?;
+    let codec = read_sound_description(reader, entry)?;
+
+    let chunk_offsets = match bmff::find(reader, stbl.start, stbl.end, b"co64")? {
+        Some(co64) => read_table(reader, co64, 8)?,
+        None => {
+            let stco = table(reader, b"stco")?;
+            read_table(reader, stco, 4)?
+        }
+    };
+    // (first chunk, samples per chunk, sample description) runs
+    let stsc = table(reader, b"stsc")?;
+    let stsc = read_table(reader, stsc, 12)?;
+    let stsc: Vec<_> = stsc.chunks_exact(3).map(|run| (run[0], run[1])).collect();
+    let stsz = table(reader, b"stsz")?;
+    reader.seek(SeekFrom::Start(stsz.start + 4))?;
+    let sample_size = u64::from(bmff::read_u32(reader)?);
+    let sample_sizes = if sample_size == 0 {
+        read_table(reader, stsz, 4)?
+    } else {
+        Vec::new()
+    };
+    // Old QuickTime files give a size of 1 for PCM, meaning one frame
+    let sample_size = match (&codec, sample_size) {
+        (Codec::Pcm(format), 1) => format.frame_size() as u64,
+        (_, size) => size,
+    };
+
+    let mut decoder = Decoder::new(codec, window)?;
+    let mut sample = 0;
+    for (i, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        let chunk = i as u64 + 1;
+        let run = stsc.partition_point(|&(first, _)| first <= chunk);
+        let samples = match run.checked_sub(1) {
+            Some(run) => stsc[run].1,
+            None => return Err(invalid()),
+        };
+        let (sizes, size) = if sample_size == 0 {
+            let start = sample.min(sample_sizes.len());
+            let end = (sample + samples as usize).min(sample_sizes.len());
+            let sizes = &sample_sizes[start..end];
+            (sizes, sizes.iter().sum())
+        } else {
+            (&[][..], samples * sample_size)
+        };
+        sample += samples as usize;
+
+        reader.seek(SeekFrom::Start(chunk_offset))?;
+        let mut data = Vec::new();
+        reader.by_ref().take(size).read_to_end(&mut data)?;
+        // Decode a sample at a time, as AAC samples are packets
+        if sample_size == 0 {
+            let mut rest = &data[..];
+            for &size in sizes {
+                let (packet, tail) = rest.split_at((size as usize).min(rest.len()));
+                decoder.push(packet)?;
+                rest = tail;
+            }
+        } else {
+            for packet in data.chunks(sample_size as usize) {
+                decoder.push(packet)?;
+            }
+        }
+        if decoder.is_full() || (data.len() as u64) < size {
+            break;
+        }
+    }
+    decoder.finish()
+}
+
+/// Read the sound sample description in `entry`, as used in QuickTime and MPEG4
+/// files.
+fn read_sound_description<R: Read + Seek>(
+    reader: &mut R,
+    entry: bmff::BoxHeader,
+) -> Result<Codec, Error> {
+    // Skip the reserved bytes and data reference index
+    reader.seek(SeekFrom::Start(entry.start + 8))?;
+    let version = bmff::read_u16(reader)?;
+    // Revision and vendor
+    reader.seek(SeekFrom::Current(6))?;
+    let mut channels = usize::from(bmff::read_u16(reader)?);
+    let sample_size = usize::from(bmff::read_u16(reader)?);
+    // Compression ID and packet size
+    reader.seek(SeekFrom::Current(4))?;
+    let mut sample_rate = f64::from(bmff::read_u32(reader)?) / 65536.;
+
+    let signed_be = |bytes| (Encoding::Signed, true, bytes);
+    let (encoding, big_endian, bytes) = match &entry.box_type {
+        b"sowt" => (Encoding::Signed, false, sample_size / 8),
+        b"twos" => signed_be(sample_size / 8),
+        b"raw " => (Encoding::Unsigned, false, 1),
+        b"in24" => signed_be(3),
+        b"in32" => signed_be(4),
+        b"fl32" => (Encoding::Float, true, 4),
+        b"fl64" => (Encoding::Float, true, 8),
+        b"lpcm" if version == 2 => {
+            // Size of the struct, then the rate and channels in place of the
+            // fields above
+            reader.seek(SeekFrom::Current(4))?;
+            sample_rate = f64::from_bits(bmff::read_u64(reader)?);
+            channels = bmff::read_u32(reader)? as usize;
+            reader.seek(SeekFrom::Current(4))?;
+            let bits = bmff::read_u32(reader)? as usize;
+            let flags = bmff::read_u32(reader)?;
+            let encoding = if flags & 1 != 0 {
+                Encoding::Float
+            } else if flags & 4 != 0 {
+                Encoding::Signed
+            } else {
+                Encoding::Unsigned
+            };
+            (encoding, flags & 2 != 0, bits / 8)
+        }
+        b"mp4a" => {
+            let (object_type, config) = read_esds(reader, entry, version)?;
+            return match object_type {
+                // MPEG4 audio, and the three MPEG2 AAC profiles
+                0x40 | 0x66..=0x68 => Codec::aac(&config),
+                0x69 | 0x6B => Err(unsupported_codec("MP3")),
+                _ => Err(unsupported_codec("mp4a")),
+            };
+        }
+        other => return Err(unsupported_codec(&String::from_utf8_lossy(other))),
+    };
+    Ok(Codec::Pcm(PcmFormat {
+        encoding,
+        big_endian,
+        bytes,
+        channels,
+        sample_rate,
+    }))
+}
+
+/// Read the `esds` box of an `mp4a` sample description of `version`, returning
+/// the object type and the decoder specific info, which for AAC is its
+/// AudioSpecificConfig.
+fn read_esds<R: Read + Seek>(
+    reader: &mut R,
+    entry: bmff::BoxHeader,
+    version: u16,
+) -> Result<(u8, Vec<u8>), Error> {
+    let invalid = || Error::UnsupportedAudio(String::from("invalid AAC sample description"));
+    // QuickTime sound descriptions have more fields in versions 1 and 2, and put
+    // the esds box in a wave box
+    let start = entry.start
+        + match version {
+            1 => 44,
+            2 => 64,
+            _ => 28,
+        };
+    let esds = match bmff::find(reader, start, entry.end, b"esds")? {
+        Some(esds) => esds,
+        None => {
+            let wave = bmff::find(reader, start, entry.end, b"wave")?.ok_or_else(invalid)?;
+            bmff::find(reader, wave.start, wave.end, b"esds")?.ok_or_else(invalid)?
+        }
+    };
+    // Skip the version and flags
+    reader.seek(SeekFrom::Start(esds.start + 4))?;
+    let mut data = Vec::new();
+    reader
+        .by_ref()
+        .take(esds.end.saturating_sub(esds.start + 4))
+        .read_to_end(&mut data)?;
+    let (object_type, info) = decoder_config(&data).ok_or_else(invalid)?;
+    Ok((object_type, info.to_vec()))
+}
+
+/// The object type and decoder specific info in the descriptors of an `esds`
+/// box.
+fn decoder_config(data: &[u8]) -> Option<(u8, &[u8])> {
+    let es = descriptor(data, 3)?;
+    // The ES ID, then flags for the optional fields
+    let flags = *es.get(2)?;
+    let mut rest = es.get(3..)?;
+    // The ES ID this one depends on
+    if flags & 0x80 != 0 {
+        rest = rest.get(2..)?;
+    }
+    // A URL with its length
+    if flags & 0x40 != 0 {
+        rest = rest.get(1 + usize::from(*rest.first()?)..)?;
+    }
+    // The OCR ES ID
+    if flags & 0x20 != 0 {
+        rest = rest.get(2..)?;
+    }
+    let config = descriptor(rest, 4)?;
+    // The object type, stream type, buffer size and bitrates come before the
+    // decoder specific info
+    let info = descriptor(config.get(13..)?, 5)?;
+    Some((config[0], info))
+}
+
+/// The contents of the descriptor at the start of `data`, if it has `tag`.
+fn descriptor(data: &[u8], tag: u8) -> Option<&[u8]> {
+    if *data.first()? != tag {
+        return None;
+    }
+    // The size is in up to four bytes of seven bits, with the top bit set on all
+    // but the last
+    let mut size = 0;
+    let mut start = 1;
+    while start < 5 {
+        let byte = *data.get(start)?;
+        start += 1;
+        size = (size << 7) | usize::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    // Some encoders give sizes past the end of the box
+    data.get(start..(start + size).min(data.len()))
+}
+
+/// Read the entries of a table box with a version, flags and entry count, each
+/// entry being `size` bytes. Entries of 12 bytes are read as three integers.
+fn read_table<R: Read + Seek>(
+    reader: &mut R,
+    header: bmff::BoxHeader,
+    size: u64,
+) -> Result<Vec<u64>, Error> {
+    // stsz has the sample size before its count
+    let count_offset = if &header.box_type == b"stsz" { 8 } else { 4 };
+    reader.seek(SeekFrom::Start(header.start + count_offset))?;
+    let count = u64::from(bmff::read_u32(reader)?);
+    let available = header.end.saturating_sub(header.start + count_offset + 4) / size;
+    let mut values = Vec::new();
+    for _ in 0..count.min(available) {
+        match size {
+            8 => values.push(bmff::read_u64(reader)?),
+            4 => values.push(u64::from(bmff::read_u32(reader)?)),
+            _ => {
+                for _ in 0..size / 4 {
+                    values.push(u64::from(bmff::read_u32(reader)?));
+                }
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Decode the first audio track of a Matroska file.
+fn read_matroska<R: Read + Seek>(reader: &mut R, window: Window) -> Result<Audio, Error> {
+    let mkv = Matroska::open(&mut *reader)?;
+    let track = mkv
+        .tracks
+        .iter()
+        .find(|track| track.tracktype == Tracktype::Audio)
+        .ok_or_else(no_audio)?;
+    let audio = match &track.settings {
+        Settings::Audio(audio) => audio,
+        _ => return Err(no_audio()),
+    };
+    let bytes = audio.bit_depth.unwrap_or(0) as usize / 8;
+    // 8-bit PCM is unsigned
+    let integer = if bytes == 1 {
+        Encoding::Unsigned
+    } else {
+        Encoding::Signed
+    };
+    let pcm = |encoding, big_endian| {
+        Codec::Pcm(PcmFormat {
+            encoding,
+            big_endian,
+            bytes,
+            channels: audio.channels as usize,
+            sample_rate: audio.sample_rate,
+        })
+    };
+    let codec = match track.codec_id.as_str() {
+        "A_PCM/INT/LIT" => pcm(integer, false),
+        "A_PCM/INT/BIG" => pcm(integer, true),
+        "A_PCM/FLOAT/IEEE" => pcm(Encoding::Float, false),
+        codec if codec.starts_with("A_AAC") => match &track.codec_private {
+            Some(config) => Codec::aac(config)?,
+            None => {
+                return Err(Error::UnsupportedAudio(String::from(
+                    "the AAC track has no AudioSpecificConfig",
+                )))
+            }
+        },
+        codec => return Err(unsupported_codec(codec)),
+    };
+    let mut decoder = Decoder::new(codec, window)?;
+
+    let end = reader.seek(SeekFrom::End(0))?;
+    let segment = ebml::find(reader, 0, end, ebml::SEGMENT)?.ok_or_else(no_audio)?;
+    let mut offset = segment.start;
+    while let Some(header) = ebml::read_header(reader, offset, segment.end)? {
+        if decoder.is_full() {
+            break;
+        }
+        offset = if header.id == ebml::CLUSTER {
+            read_cluster(reader, header, track.number, &mut decoder)?
+        } else if header.unknown_size {
+            break;
+        } else {
+            header.end
+        };
+    }
+    decoder.finish()
+}
+
+/// Decode the blocks of `track` in a cluster, returning the offset of the element
+/// after it.
+///
+/// Clusters of unknown size end where the next cluster starts.
+fn read_cluster<R: Read + Seek>(
+    reader: &mut R,
+    cluster: ebml::ElementHeader,
+    track: u64,
+    decoder: &mut Decoder,
+) -> Result<u64, Error> {
+    let mut offset = cluster.start;
+    while let Some(header) = ebml::read_header(reader, offset, cluster.end)? {
+        match header.id {
+            ebml::CLUSTER => return Ok(offset),
+            ebml::SIMPLE_BLOCK => read_block(reader, header, track, decoder)?,
+            ebml::BLOCK_GROUP => {
+                if let Some(block) = ebml::find(reader, header.start, header.end, ebml::BLOCK)? {
+                    read_block(reader, block, track, decoder)?;
+                }
+            }
+            _ => (),
+        }
+        if header.unknown_size || decoder.is_full() {
+            return Ok(header.end);
+        }
+        offset = header.end;
+    }
+    Ok(cluster.end)
+}
+
+/// Decode a block if it belongs to `track`.
+fn read_block<R: Read + Seek>(
+    reader: &mut R,
+    block: ebml::ElementHeader,
+    track: u64,
+    decoder: &mut Decoder,
+) -> Result<(), Error> {
+    reader.seek(SeekFrom::Start(block.start))?;
+    let mut data = Vec::new();
+    reader
+        .by_ref()
+        .take(block.end - block.start)
+        .read_to_end(&mut data)?;
+    for frame in block_frames(&data, track)?.unwrap_or_default() {
+        decoder.push(frame)?;
+    }
+    Ok(())
+}
+
+/// The frames in a block, if it belongs to `track`.
+fn block_frames(data: &[u8], track: u64) -> Result<Option<Vec<&[u8]>>, Error> {
+    let mut cursor = Cursor::new(data);
+    let (number, _) = ebml::read_vint(&mut cursor, true)?;
+    if number != track {
+        return Ok(None);
+    }
+    // Timestamp relative to the cluster, then flags
+    cursor.seek(SeekFrom::Current(2))?;
+    let flags = bmff::read_u8(&mut cursor)?;
+    let lacing = (flags >> 1) & 3;
+    // The number of frames after the first
+    let laced = match lacing {
+        0 => 0,
+        _ => usize::from(bmff::read_u8(&mut cursor)?),
+    };
+
+    // The sizes of the frames, but for the last which takes the rest of the block
+    let mut sizes = Vec::with_capacity(laced);
+    match lacing {
+        // Xiph lacing, with each size in runs of 255
+        1 => {
+            for _ in 0..laced {
+                let mut size = 0;
+                loop {
+                    let byte = bmff::read_u8(&mut cursor)?;
+                    size += u64::from(byte);
+                    if byte != 255 {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        // EBML lacing, with the first size then signed differences as variable
+        // length integers
+        3 => {
+            let mut size = 0;
+            for i in 0..laced {
+                let (value, len) = ebml::read_vint(&mut cursor, true)?;
+                size = if i == 0 {
+                    value
+                } else {
+                    // Signed values are offset by half their range
+                    let bias = (1 << (7 * len - 1)) - 1;
+                    size.wrapping_add(value).wrapping_sub(bias)
+                };
+                sizes.push(size);
+            }
+        }
+        _ => (),
+    }
+
+    let mut rest = data.get(cursor.position() as usize..).unwrap_or_default();
+    // Fixed size lacing, with equal frames
+    if lacing == 2 {
+        sizes.resize(laced, (rest.len() / (laced + 1)) as u64);
+    }
+    let mut frames = Vec::with_capacity(laced + 1);
+    for size in sizes {
+        if size > rest.len() as u64 {
+            return Err(ebml::invalid_data("invalid lace sizes").into());
+        }
+        let (frame, tail) = rest.split_at(size as usize);
+        frames.push(frame);
+        rest = tail;
+    }
+    frames.push(rest);
+    Ok(Some(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm(encoding: Encoding, big_endian: bool, bytes: usize, channels: usize) -> PcmFormat {
+        PcmFormat {
+            encoding,
+            big_endian,
+            bytes,
+            channels,
+            sample_rate: 8000.,
+        }
+    }
+
+    #[test]
+    fn test_decode_sample() {
+        let s16le = pcm(Encoding::Signed, false, 2, 1);
+        assert_eq!(s16le.decode(&[0x00, 0x40]), 0.5);
+        assert_eq!(s16le.decode(&[0x00, 0xC0]), -0.5);
+        let s24be = pcm(Encoding::Signed, true, 3, 1);
+        assert_eq!(s24be.decode(&[0xC0, 0x00, 0x00]), -0.5);
+        let u8 = pcm(Encoding::Unsigned, false, 1, 1);
+        assert_eq!(u8.decode(&[0x80]), 0.);
+        assert_eq!(u8.decode(&[0x40]), -0.5);
+        let f32be = pcm(Encoding::Float, true, 4, 1);
+        assert_eq!(f32be.decode(&0.25f32.to_be_bytes()), 0.25);
+        let f64le = pcm(Encoding::Float, false, 8, 1);
+        assert_eq!(f64le.decode(&(-0.25f64).to_le_bytes()), -0.25);
+    }
+
+    #[test]
+    fn test_decoder() {
+        // Stereo at four times the output rate, split mid-frame
+        let codec = || Codec::Pcm(pcm(Encoding::Signed, false, 2, 2));
+        let mut decoder = Decoder::new(codec(), Window::START).unwrap();
+        let frame = |left: i16, right: i16| [left.to_le_bytes(), right.to_le_bytes()].concat();
+        let mut data = Vec::new();
+        for _ in 0..4 {
+            data.extend(frame(0x4000, 0));
+        }
+        for _ in 0..4 {
+            data.extend(frame(-0x4000, -0x4000));
+        }
+        decoder.push(&data[..5]).unwrap();
+        decoder.push(&data[5..]).unwrap();
+        let audio = decoder.finish().unwrap();
+        assert_eq!(audio.samples(), [0.25, -0.5]);
+        assert_eq!(audio.start(), Duration::ZERO);
+
+        // Skipping the first output sample
+        let window = Window {
+            start: Duration::seconds_f64(1. / f64::from(SAMPLE_RATE)),
+            length: Duration::SECOND,
+        };
+        let mut decoder = Decoder::new(codec(), window).unwrap();
+        decoder.push(&data).unwrap();
+        let audio = decoder.finish().unwrap();
+        assert_eq!(audio.samples(), [-0.5]);
+        assert_eq!(audio.start(), window.start);
+
+        // Or all of them
+        let window = Window {
+            start: Duration::SECOND,
+            length: Duration::SECOND,
+        };
+        let mut decoder = Decoder::new(codec(), window).unwrap();
+        decoder.push(&data).unwrap();
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn test_aac_decoder() {
+        // Two silent frames of stereo AAC-LC at 44.1kHz
+        let mut decoder = Decoder::new(Codec::aac(&[0x12, 0x10]).unwrap(), Window::START).unwrap();
+        let silence = [0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00, 0x23, 0x80];
+        decoder.push(&silence).unwrap();
+        decoder.push(&silence).unwrap();
+        assert_eq!(decoder.sample_rate, 44100.);
+        let audio = decoder.finish().unwrap();
+        assert_eq!(audio.samples().len(), 93);
+        assert!(audio.samples().iter().all(|&sample| sample == 0.));
+
+        // 5.1 channels aren't supported
+        assert!(Codec::aac(&[0x12, 0x30]).is_err());
+    }
+
+    #[test]
+    fn test_decoder_config() {
+        let asc = [0x12, 0x10];
+        // ES descriptor with a four byte size, then the decoder config and specific
+        // info
+        let mut esds = vec![0x03, 0x80, 0x80, 0x80, 25, 0, 1, 0];
+        esds.extend([0x04, 17, 0x40, 0x15, 0, 0, 0, 0, 1, 0xF4, 0, 0, 1, 0xF4, 0]);
+        esds.extend([0x05, 2]);
+        esds.extend(asc);
+        esds.extend([0x06, 1, 2]);
+        assert_eq!(decoder_config(&esds), Some((0x40, &asc[..])));
+
+        // With a URL, and a size past the end
+        let mut esds = vec![0x03, 40, 0, 1, 0x40, 3, b'a', b'b', b'c'];
+        esds.extend([0x04, 17, 0x6B, 0x15, 0, 0, 0, 0, 1, 0xF4, 0, 0, 1, 0xF4, 0]);
+        esds.extend([0x05, 2]);
+        esds.extend(asc);
+        assert_eq!(decoder_config(&esds), Some((0x6B, &asc[..])));
+
+        assert_eq!(decoder_config(&esds[..12]), None);
+        assert_eq!(decoder_config(&[0x04, 0]), None);
+    }
+
+    #[test]
+    fn test_block_frames() {
+        // Track 1, timestamp 0, no lacing
+        let block = [0x81, 0, 0, 0x80, 1, 2];
+        assert_eq!(block_frames(&block, 1).unwrap(), Some(vec![&[1, 2][..]]));
+        assert_eq!(block_frames(&block, 2).unwrap(), None);
+        // Xiph lacing of three frames of 300, 2 and 1 bytes
+        let mut block = vec![0x81, 0, 0, 0x02, 2, 255, 45, 2];
+        block.extend([0; 303]);
+        let sizes: Vec<_> = block_frames(&block, 1)
+            .unwrap()
+            .unwrap()
+            .iter()
+            .map(|frame| frame.len())
+            .collect();
+        assert_eq!(sizes, [300, 2, 1]);
+        // EBML lacing of frames of 2, 3 and 1 bytes
+        let block = [0x81, 0, 0, 0x06, 2, 0x82, 0xC0, 1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            block_frames(&block, 1).unwrap(),
+            Some(vec![&[1, 2][..], &[3, 4, 5], &[6]])
+        );
+        // Fixed size lacing of two frames
+        let block = [0x81, 0, 0, 0x04, 1, 1, 2, 3, 4];
+        assert_eq!(
+            block_frames(&block, 1).unwrap(),
+            Some(vec![&[1, 2][..], &[3, 4]])
+        );
+        // Sizes past the end of the block
+        let block = [0x81, 0, 0, 0x02, 1, 3, 1, 2];
+        assert!(block_frames(&block, 1).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+//! Minimal reading of ISO base media file format (ISO-BMFF) boxes, as used by
+//! MPEG4, QuickTime and HEIF files.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The location of a box within a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BoxHeader {
+    pub box_type: [u8; 4],
+    /// Offset of the start of the box payload, after the header
+    pub start: u64,
+    /// Offset of the end of the box
+    pub end: u64,
+}
+
+/// Read the header of the box at `offset`, if there is one before `end`.
+///
+/// `end` is the end of the parent box (or file), which the box must fit within.
+pub(crate) fn read_header<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    end: u64,
+) -> io::Result<Option<BoxHeader>> {
+    if offset.saturating_add(8) > end {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+    let size = u64::from(read_u32(reader)?);
+    let mut box_type = [0; 4];
+    reader.read_exact(&mut box_type)?;
+
+    let (start, box_end) = match size {
+        // The box extends to the end of the file (or parent)
+        0 => (offset + 8, Some(end)),
+        // 64-bit size follows the type
+        1 => (offset + 16, offset.checked_add(read_u64(reader)?)),
+        size => (offset + 8, offset.checked_add(size)),
+    };
+    match box_end {
+        Some(box_end) if start <= box_end && box_end <= end => Ok(Some(BoxHeader {
+            box_type,
+            start,
+            end: box_end,
+        })),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid box size",
+        )),
+    }
+}
+
+/// Find the first box of type `box_type` between `start` and `end`.
+pub(crate) fn find<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    box_type: &[u8; 4],
+) -> io::Result<Option<BoxHeader>> {
+    let mut offset = start;
+    while let Some(header) = read_header(reader, offset, end)? {
+        if &header.box_type == box_type {
+            return Ok(Some(header));
+        }
+        offset = header.end;
+    }
+    Ok(None)
+}
+
+/// Find a box by following `path` down from the top level of the file.
+pub(crate) fn find_path<R: Read + Seek>(
+    reader: &mut R,
+    path: &[&[u8; 4]],
+) -> io::Result<Option<BoxHeader>> {
+    let mut start = 0;
+    let mut end = reader.seek(SeekFrom::End(0))?;
+    let mut found = None;
+    for box_type in path {
+        match find(reader, start, end, box_type)? {
+            Some(header) => {
+                start = header.start;
+                end = header.end;
+                found = Some(header);
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(found)
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Read an unsigned integer that is `size` bytes long, as used in `iloc` boxes.
+pub(crate) fn read_uint<R: Read>(reader: &mut R, size: u8) -> io::Result<u64> {
+    match size {
+        0 => Ok(0),
+        4 => read_u32(reader).map(u64::from),
+        8 => read_u64(reader),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid integer size",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_header() {
+        let mut data = 24u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&[0; 16]);
+        let header = |data: &[u8], end| read_header(&mut Cursor::new(data), 0, end);
+        assert_eq!(
+            header(&data, 24).unwrap(),
+            Some(BoxHeader {
+                box_type: *b"moov",
+                start: 8,
+                end: 24
+            })
+        );
+        assert_eq!(header(&data, 4).unwrap(), None);
+
+        // Boxes that extend past their parent, or end before their header
+        assert!(header(&data, 16).is_err());
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        large.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(header(&large, 24).is_err());
+        large[8..16].copy_from_slice(&8u64.to_be_bytes());
+        assert!(header(&large, 24).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Per-camera rules, read from a TOML file.
+//!
+//! A rules file holds a list of `[[rule]]` tables. Each rule has conditions on
+//! the file and its metadata, and settings that apply to the files it matches:
+//!
+//! ```toml
+//! [[rule]]
+//! name = "gopro"
+//! make = "GoPro"
+//! tz = "Europe/Berlin"
+//! format = "[year]-[month]-[day]_[hour]-[minute]-[second]_{stem}.{ext}"
+//! date-source = ["track", "container"]
+//!
+//! [[rule]]
+//! writing-app = "OBS*"
+//! tz-offset = -5
+//! ```
+//!
+//! | Condition     | Matches                                                      |
+//! |---------------|--------------------------------------------------------------|
+//! | `make`        | Make of the camera                                           |
+//! | `model`       | Model of the camera                                          |
+//! | `brand`       | MPEG4 `ftyp` major brand, e.g. `qt` or `mp42`                |
+//! | `encoder`     | MPEG4 encoder (`©too`), e.g. `Lavf58.76.100`                 |
+//! | `writing-app` | Matroska writing application                                 |
+//! | `muxing-app`  | Matroska muxing application                                  |
+//! | `path`        | Path of the file, relative to the rules file if it has a `/` |
+//!
+//! Conditions are case-insensitive glob patterns, and a rule matches a file when
+//! all of its conditions do. Files take their settings from the first rule they
+//! match. The settings are `tz-offset` (hours) or `tz`, `format` and
+//! `date-source`, as on the command line, which they override.
+//!
+
+use std::fs;
+use std::path::{self, Path, PathBuf};
+
+use glob::Pattern;
+use serde::Deserialize;
+use time::Duration;
+
+use crate::source::{SourceKind, SourceOrder};
+use crate::template::Template;
+use crate::tz::TimeZone;
+use crate::{walk, Device, Error};
+
+/// The rules read from a rules file.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub rules: Vec<Rule>,
+}
+
+/// A rule for the files from a camera or app.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// The `name` of the rule, or its position in the file, counting from 1
+    pub name: String,
+    conditions: Vec<(Field, Pattern)>,
+    /// The directory of the rules file, which paths are relative to
+    root: PathBuf,
+    pub settings: Settings,
+}
+
+/// The settings a rule applies. Settings that are `None` are left as they are on
+/// the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// The offset to add to local times
+    pub offset: Option<Duration>,
+    /// The time zone of local times
+    pub zone: Option<TimeZone>,
+    pub template: Option<Template>,
+    pub sources: Option<Vec<SourceKind>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Make,
+    Model,
+    Brand,
+    Encoder,
+    WritingApp,
+    MuxingApp,
+    Path,
+}
+
+/// The contents of a rules file, as written.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rule: Vec<RuleTable>,
+}
+
+/// A `[[rule]]` table, as written.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RuleTable {
+    name: Option<String>,
+    make: Option<String>,
+    model: Option<String>,
+    brand: Option<String>,
+    encoder: Option<String>,
+    writing_app: Option<String>,
+    muxing_app: Option<String>,
+    path: Option<String>,
+    tz_offset: Option<f64>,
+    tz: Option<String>,
+    format: Option<String>,
+    date_source: Option<DateSources>,
+}
+
+/// The `date-source` of a rule, as an array or a comma separated string like
+/// `--date-source`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DateSources {
+    List(Vec<String>),
+    String(String),
+}
+
+impl Config {
+    /// Read the rules file at `path`.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let text = fs::read_to_string(path)?;
+        let root = path::absolute(path)?
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        Config::parse(&text, &root).map_err(|msg| Error::InvalidConfig(path.to_path_buf(), msg))
+    }
+
+    /// Parse the contents of a rules file, with paths relative to `root`.
+    pub fn parse(text: &str, root: &Path) -> Result<Config, String> {
+        let file: RulesFile =
+            toml::from_str(text).map_err(|err| String::from(err.to_string().trim_end()))?;
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(i, table)| Rule::from_table(i + 1, table, root))
+            .collect::<Result<_, _>>()?;
+        Ok(Config { rules })
+    }
+
+    /// The first rule that matches the file at `path` from `device`.
+    pub fn find(&self, path: &Path, device: &Device) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(path, device))
+    }
+
+    /// All the sources used by the rules, for reading every date they might need.
+    pub fn sources(&self) -> impl Iterator<Item = SourceKind> + '_ {
+        self.rules
+            .iter()
+            .filter_map(|rule| rule.settings.sources.as_deref())
+            .flatten()
+            .copied()
+    }
+}
+
+impl Rule {
+    fn from_table(number: usize, table: RuleTable, root: &Path) -> Result<Rule, String> {
+        let name = table.name.unwrap_or_else(|| number.to_string());
+        let error = |msg: String| format!("rule {}: {}", name, msg);
+
+        let fields = [
+            (Field::Make, "make", table.make),
+            (Field::Model, "model", table.model),
+            (Field::Brand, "brand", table.brand),
+            (Field::Encoder, "encoder", table.encoder),
+            (Field::WritingApp, "writing-app", table.writing_app),
+            (Field::MuxingApp, "muxing-app", table.muxing_app),
+            (Field::Path, "path", table.path),
+        ];
+        let mut conditions = Vec::new();
+        for (field, key, pattern) in fields {
+            if let Some(pattern) = pattern {
+                let pattern = Pattern::new(&pattern)
+                    .map_err(|err| error(format!("invalid pattern for {}: {}", key, err)))?;
+                conditions.push((field, pattern));
+            }
+        }
+
+        let mut settings = Settings::default();
+        if let Some(hours) = table.tz_offset {
+            let seconds = (hours * 60. * 60.).round();
+            if seconds.abs() > f64::from(i32::MAX) {
+                return Err(error(String::from("tz-offset too big")));
+            }
+            settings.offset = Some(Duration::seconds(seconds as i64));
+        }
+        if let Some(name) = table.tz {
+            let zone = TimeZone::load(&name)
+                .map_err(|err| error(format!("unable to load time zone {}: {}", name, err)))?;
+            settings.zone = Some(zone);
+        }
+        if let Some(format) = table.format {
+            let template = format.parse::<Template>();
+            settings.template = Some(template.map_err(|err| error(err.to_string()))?);
+        }
+        if let Some(sources) = table.date_source {
+            let order = match sources {
+                DateSources::List(names) => names.join(",").parse(),
+                DateSources::String(names) => names.parse(),
+            };
+            let SourceOrder(kinds) = order.map_err(error)?;
+            settings.sources = Some(kinds);
+        }
+        if settings.offset.is_some() && settings.zone.is_some() {
+            return Err(error(String::from(
+                "tz and tz-offset can't be used together",
+            )));
+        }
+        Ok(Rule {
+            name,
+            conditions,
+            root: root.to_path_buf(),
+            settings,
+        })
+    }
+
+    /// Whether the file at `path` from `device` meets all the conditions of this
+    /// rule. Metadata missing from the file never matches.
+    pub fn matches(&self, path: &Path, device: &Device) -> bool {
+        const OPTIONS: glob::MatchOptions = glob::MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: false,
+            require_literal_leading_dot: false,
+        };
+        self.conditions.iter().all(|(field, pattern)| {
+            let value = match field {
+                Field::Make => &device.make,
+                Field::Model => &device.model,
+                Field::Brand => &device.brand,
+                Field::Encoder => &device.encoder,
+                Field::WritingApp => &device.writing_app,
+                Field::MuxingApp => &device.muxing_app,
+                Field::Path => {
+                    let path = path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+                    return walk::matches(pattern, &self.root, &path);
+                }
+            };
+            value
+                .as_deref()
+                .is_some_and(|value| pattern.matches_with(value.trim(), OPTIONS))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+# Cameras
+[[rule]]
+name = "gopro"
+make = "gopro"
+tz-offset = -5.5
+format = "[year]{stem}"
+date-source = ["track", "container"]
+
+[[rule]]
+path = "obs/*.mkv"  # Recordings
+writing-app = 'OBS*'
+date-source = [
+    "filename",
+    "container",
+]
+"#;
+
+    #[test]
+    fn test_rules() {
+        let root = Path::new("/videos");
+        let config = Config::parse(RULES, root).unwrap();
+        let gopro = &config.rules[0];
+        assert_eq!(gopro.settings.offset, Some(Duration::minutes(-330)));
+        assert_eq!(
+            gopro.settings.sources,
+            Some(vec![SourceKind::Track, SourceKind::Container])
+        );
+
+        let device = |make: &str, writing_app: &str| Device {
+            make: Some(String::from(make)).filter(|s| !s.is_empty()),
+            writing_app: Some(String::from(writing_app)).filter(|s| !s.is_empty()),
+            ..Device::default()
+        };
+        let find = |path: &str, device: &Device| {
+            config
+                .find(Path::new(path), device)
+                .map(|rule| rule.name.as_str())
+        };
+        assert_eq!(
+            find("/videos/GX010123.MP4", &device("GoPro", "")),
+            Some("gopro")
+        );
+        assert_eq!(
+            find(
+                "/videos/obs/2023-04-12 14-39-01.mkv",
+                &device("", "OBS 29.1")
+            ),
+            Some("2")
+        );
+        assert_eq!(
+            find("/videos/2023-04-12 14-39-01.mkv", &device("", "OBS 29.1")),
+            None
+        );
+        assert_eq!(find("/videos/obs/a.mkv", &device("", "mkvmerge")), None);
+
+        let rule = |toml: &str| Config::parse(&format!("[[rule]]\n{}", toml), root);
+        let second = rule("date-source = \"filename,container\"").unwrap();
+        assert_eq!(
+            second.rules[0].settings.sources,
+            config.rules[1].settings.sources
+        );
+        assert!(Config::parse("", root).unwrap().rules.is_empty());
+        assert!(Config::parse("make = \"GoPro\"", root).is_err());
+        assert!(Config::parse("[rule]\nmake = \"GoPro\"", root).is_err());
+        assert!(rule("make = \"x").is_err());
+        assert!(rule("tz = \"Europe/Berlin\"\ntz-offset = 1").is_err());
+        assert!(Config::parse("[[rule]]\ncolour = \"red\"", root).is_err());
+        assert!(Config::parse("[[rule]]\nmake = \"a\"\nmake = \"b\"", root).is_err());
+        assert!(Config::parse("[[rule]]\ntz-offset = \"2\"", root).is_err());
+        assert!(Config::parse("[[rule]]\ndate-source = \"mtime\"", root).is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Correcting for camera clocks that drift.
+//!
+//! A reference is a file recorded at a known time, e.g. a clip of a phone
+//! showing the time. Comparing the time from the file with the true time gives
+//! the error of the clock of the camera that recorded it, which is then
+//! corrected in every file from the same camera. With references at two or more
+//! times the error is interpolated linearly between them, and extrapolated from
+//! the nearest two beyond them, so clocks that gain or lose time steadily are
+//! corrected throughout.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use time::format_description::well_known::Rfc3339;
+use time::{Duration, OffsetDateTime};
+
+use crate::Device;
+
+/// A file and the time it was really recorded, parsed from `PATH=TIME` with the
+/// time in RFC 3339 format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub path: PathBuf,
+    pub datetime: OffsetDateTime,
+}
+
+/// The metadata identifying the camera a file came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    make: Option<String>,
+    model: Option<String>,
+    encoder: Option<String>,
+}
+
+/// The clock errors measured for each camera.
+#[derive(Debug, Clone, Default)]
+pub struct DriftCorrection {
+    /// The times shown by the camera clock, in order, and the correction needed
+    /// at each
+    points: HashMap<DeviceId, Vec<(OffsetDateTime, Duration)>>,
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, datetime) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected PATH=TIME, got '{}'", s))?;
+        let datetime = OffsetDateTime::parse(datetime, &Rfc3339)
+            .map_err(|err| format!("invalid time '{}': {}", datetime, err))?;
+        Ok(Reference {
+            path: PathBuf::from(path),
+            datetime,
+        })
+    }
+}
+
+impl DeviceId {
+    /// The identity of the camera `device` describes, if its metadata says
+    /// anything about it.
+    pub fn new(device: &Device) -> Option<DeviceId> {
+        let id = DeviceId {
+            make: device.make.clone(),
+            model: device.model.clone(),
+            encoder: device.encoder.clone(),
+        };
+        (id.make.is_some() || id.model.is_some() || id.encoder.is_some()).then_some(id)
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [&self.make, &self.model, &self.encoder];
+        let parts = parts.iter().filter_map(|part| part.as_deref());
+        f.write_str(&parts.collect::<Vec<_>>().join(" "))
+    }
+}
+
+impl DriftCorrection {
+    pub fn new() -> Self {
+        DriftCorrection::default()
+    }
+
+    /// Record that the clock of `device` showed `clock` at the true time `actual`.
+    pub fn add(
+        &mut self,
+        device: DeviceId,
+        clock: OffsetDateTime,
+        actual: OffsetDateTime,
+    ) -> Result<(), String> {
+        let points = self.points.entry(device.clone()).or_default();
+        if points.iter().any(|&(time, _)| time == clock) {
+            return Err(format!(
+                "two references for {} have the same recorded time",
+                device
+            ));
+        }
+        let i = points.partition_point(|&(time, _)| time < clock);
+        points.insert(i, (clock, actual - clock));
+        Ok(())
+    }
+
+    /// The correction to add to `clock`, a time shown by the clock of `device`, or
+    /// `None` if there are no references for the device.
+    pub fn correction(&self, device: &DeviceId, clock: OffsetDateTime) -> Option<Duration> {
+        let points = self.points.get(device)?;
+        let (a, b) = match points.as_slice() {
+            [] => return None,
+            [(_, correction)] => return Some(*correction),
+            _ => {
+                // The segment containing clock, or the nearest one
+                let i = points
+                    .partition_point(|&(time, _)| time < clock)
+                    .clamp(1, points.len() - 1);
+                (points[i - 1], points[i])
+            }
+        };
+        let ((t0, c0), (t1, c1)) = (a, b);
+        let change = (c1 - c0).whole_nanoseconds();
+        let span = (t1 - t0).whole_nanoseconds();
+        let elapsed = (clock - t0).whole_nanoseconds();
+        let nanos = c0.whole_nanoseconds() + change * elapsed / span;
+        Some(Duration::nanoseconds(i64::try_from(nanos).ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            "clips/a=b.mp4=2023-07-01T12:00:05+02:00".parse(),
+            Ok(Reference {
+                path: PathBuf::from("clips/a=b.mp4"),
+                datetime: datetime!(2023-07-01 12:00:05 +2),
+            })
+        );
+        assert!("GX010001.MP4".parse::<Reference>().is_err());
+        assert!("GX010001.MP4=yesterday".parse::<Reference>().is_err());
+    }
+
+    #[test]
+    fn test_correction() {
+        let gopro = DeviceId::new(&Device {
+            make: Some(String::from("GoPro")),
+            ..Device::default()
+        })
+        .unwrap();
+        let other = DeviceId::new(&Device {
+            encoder: Some(String::from("Lavf")),
+            ..Device::default()
+        })
+        .unwrap();
+        assert_eq!(DeviceId::new(&Device::default()), None);
+
+        let mut drift = DriftCorrection::new();
+        // Slow by a minute on the 1st, and by 11 minutes ten days later
+        drift
+            .add(
+                gopro.clone(),
+                datetime!(2023-07-01 12:00 UTC),
+                datetime!(2023-07-01 12:01 UTC),
+            )
+            .unwrap();
+        let correction = |clock| drift.correction(&gopro, clock);
+        assert_eq!(
+            correction(datetime!(2023-08-01 0:00 UTC)),
+            Some(Duration::MINUTE)
+        );
+
+        drift
+            .add(
+                gopro.clone(),
+                datetime!(2023-07-11 12:00 UTC),
+                datetime!(2023-07-11 12:11 UTC),
+            )
+            .unwrap();
+        let correction = |clock| drift.correction(&gopro, clock);
+        assert_eq!(
+            correction(datetime!(2023-07-06 12:00 UTC)),
+            Some(Duration::minutes(6))
+        );
+        assert_eq!(
+            correction(datetime!(2023-06-30 12:00 UTC)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            correction(datetime!(2023-07-12 12:00 UTC)),
+            Some(Duration::minutes(12))
+        );
+        assert_eq!(
+            drift.correction(&other, datetime!(2023-07-06 12:00 UTC)),
+            None
+        );
+
+        assert!(drift
+            .add(
+                gopro,
+                datetime!(2023-07-11 12:00 UTC),
+                datetime!(2023-07-11 12:00 UTC)
+            )
+            .is_err());
+    }
+}
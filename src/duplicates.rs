@@ -0,0 +1,275 @@
+//! Finding files that have already been seen under another name.
+//!
+//! Files are grouped by creation date, duration and size, which only needs their
+//! metadata, and only files in the same group are hashed to check that their
+//! contents are the same. The files seen are kept in an [`Index`], which can be
+//! saved as a JSON Lines file in an archive, so that later runs find duplicates of
+//! the files already there.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::rename::{hash_file, to_hex};
+use crate::{Error, Metadata};
+
+/// The name of the index kept in the root of `--dest` when no other location is
+/// chosen.
+pub const DEFAULT_INDEX_NAME: &str = ".mkv-rename-index.jsonl";
+
+/// What to do with a file that duplicates one seen before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateAction {
+    /// Warn about it, and process it like any other file
+    Report,
+    /// Leave it where it is
+    Skip,
+    /// Give its new path to the file it duplicates with a hard link, so the data
+    /// is only stored once
+    HardLink,
+    /// Put it in a separate directory, for checking before it's deleted
+    Quarantine,
+}
+
+/// A file seen before.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// The absolute path of the file
+    pub path: PathBuf,
+    /// The creation date of the file as read from it, without any offset
+    #[serde(with = "time::serde::rfc3339")]
+    pub creation_date: OffsetDateTime,
+    /// The duration of the file in milliseconds, if it has one
+    pub duration_ms: Option<i64>,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The SHA-256 hash of the file in hex, once it has been needed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// The files seen so far, to check new files against.
+#[derive(Debug, Default)]
+pub struct Index {
+    entries: Vec<IndexEntry>,
+    /// The file the index is saved in, if it is saved
+    path: Option<PathBuf>,
+}
+
+impl FromStr for DuplicateAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(DuplicateAction::Report),
+            "skip" => Ok(DuplicateAction::Skip),
+            "hardlink" => Ok(DuplicateAction::HardLink),
+            "quarantine" => Ok(DuplicateAction::Quarantine),
+            _ => Err(format!(
+                "unknown duplicate action '{}', expected report, skip, hardlink, or quarantine",
+                s
+            )),
+        }
+    }
+}
+
+impl IndexEntry {
+    /// An entry for the file at `path`, which should be absolute.
+    pub fn new(path: PathBuf, metadata: &Metadata) -> io::Result<IndexEntry> {
+        let size = fs::metadata(&path)?.len();
+        Ok(IndexEntry {
+            path,
+            creation_date: metadata.creation_date.datetime,
+            duration_ms: metadata
+                .duration
+                .map(|duration| duration.whole_milliseconds() as i64),
+            size,
+            sha256: None,
+        })
+    }
+
+    /// Whether the file might be the same as the file of `other`, without looking
+    /// at their contents.
+    fn matches(&self, other: &IndexEntry) -> bool {
+        self.creation_date == other.creation_date
+            && self.duration_ms == other.duration_ms
+            && self.size == other.size
+    }
+
+    /// Whether the file is still there, at the same size.
+    fn unchanged(&self) -> bool {
+        matches!(fs::metadata(&self.path), Ok(metadata) if metadata.len() == self.size)
+    }
+
+    /// The hash of the file, computed if it isn't known yet.
+    fn hash(&mut self) -> io::Result<&str> {
+        if self.sha256.is_none() {
+            let (hash, _) = hash_file(&self.path)?;
+            self.sha256 = Some(to_hex(&hash));
+        }
+        Ok(self.sha256.as_deref().unwrap_or_default())
+    }
+}
+
+impl Index {
+    /// An empty index that isn't saved.
+    pub fn new() -> Self {
+        Index::default()
+    }
+
+    /// Read the index saved at `path`, which is empty if there isn't one yet.
+    ///
+    /// Files that have been removed or changed size since they were added are
+    /// left out. New entries are appended to the file if `save` is set.
+    pub fn load(path: &Path, save: bool) -> Result<Index, Error> {
+        let mut index = Index {
+            entries: Vec::new(),
+            path: save.then(|| path.to_path_buf()),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(err) => return Err(err.into()),
+        };
+
+        // Later entries for a file replace earlier ones, e.g. once it's hashed
+        let mut positions = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: IndexEntry = serde_json::from_str(&line)
+                .map_err(|err| Error::InvalidIndex(path.to_path_buf(), err))?;
+            match positions.get(&entry.path) {
+                Some(&i) => index.entries[i] = entry,
+                None => {
+                    positions.insert(entry.path.clone(), index.entries.len());
+                    index.entries.push(entry);
+                }
+            }
+        }
+        index.entries.retain(IndexEntry::unchanged);
+        Ok(index)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Add `entry` to the index, replacing any entry for the same path.
+    pub fn insert(&mut self, entry: IndexEntry) -> Result<(), Error> {
+        self.save(&entry)?;
+        match self
+            .entries
+            .iter_mut()
+            .find(|other| other.path == entry.path)
+        {
+            Some(other) => *other = entry,
+            None => self.entries.push(entry),
+        }
+        Ok(())
+    }
+
+    /// Find a file in the index with the same contents as the file of `entry`,
+    /// other than the file itself.
+    ///
+    /// The hashes of the files compared are filled in, in `entry` and the index.
+    pub fn find_duplicate(&mut self, entry: &mut IndexEntry) -> Result<Option<PathBuf>, Error> {
+        let mut i = 0;
+        while i < self.entries.len() {
+            let other = &mut self.entries[i];
+            if other.path == entry.path || !other.matches(entry) {
+                i += 1;
+                continue;
+            }
+            // The file may have been removed since it was added
+            if !other.unchanged() {
+                self.entries.remove(i);
+                continue;
+            }
+            let hashed = other.sha256.is_none();
+            let other_hash = other.hash()?.to_owned();
+            if hashed {
+                let other = self.entries[i].clone();
+                self.save(&other)?;
+            }
+            if entry.hash()? == other_hash {
+                return Ok(Some(self.entries[i].path.clone()));
+            }
+            i += 1;
+        }
+        Ok(None)
+    }
+
+    /// Append `entry` to the file the index is saved in, if there is one.
+    fn save(&self, entry: &IndexEntry) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut line =
+            serde_json::to_vec(entry).map_err(|err| Error::InvalidIndex(path.clone(), err))?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_find_duplicate() {
+        let dir = env::temp_dir().join(format!("mkv-rename-duplicates-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let entry = |name: &str, contents: &[u8]| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            IndexEntry {
+                path,
+                creation_date: OffsetDateTime::from_unix_timestamp(1681265941).unwrap(),
+                duration_ms: Some(2500),
+                size: contents.len() as u64,
+                sha256: None,
+            }
+        };
+        let original = entry("IMG_4792.mov", b"video");
+        let mut copy = entry("IMG_4792 (1).mov", b"video");
+        let mut same_size = entry("IMG_4793.mov", b"other");
+
+        let index_path = dir.join(DEFAULT_INDEX_NAME);
+        let mut index = Index::load(&index_path, true).unwrap();
+        assert!(index.is_empty());
+        index.insert(original.clone()).unwrap();
+        assert_eq!(index.find_duplicate(&mut same_size).unwrap(), None);
+        assert_eq!(
+            index.find_duplicate(&mut copy).unwrap(),
+            Some(original.path.clone())
+        );
+        assert_eq!(
+            copy.sha256.as_deref(),
+            Some(to_hex(&hash_file(&copy.path).unwrap().0).as_str())
+        );
+
+        // The index is saved, with the hash worked out for the original
+        let mut saved = Index::load(&index_path, false).unwrap();
+        assert_eq!(saved.entries.len(), 1);
+        assert_eq!(saved.entries[0].sha256, copy.sha256);
+        fs::remove_file(&original.path).unwrap();
+        assert_eq!(saved.find_duplicate(&mut copy).unwrap(), None);
+        assert!(Index::load(&index_path, false).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//! Minimal reading of EBML elements, as used by Matroska files, for the parts
+//! that the matroska crate doesn't read.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+pub(crate) const SEGMENT: u32 = 0x1853_8067;
+pub(crate) const CLUSTER: u32 = 0x1F43_B675;
+pub(crate) const SIMPLE_BLOCK: u32 = 0xA3;
+pub(crate) const BLOCK_GROUP: u32 = 0xA0;
+pub(crate) const BLOCK: u32 = 0xA1;
+
+/// The location of an element within a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ElementHeader {
+    pub id: u32,
+    /// Offset of the start of the element data, after the header
+    pub start: u64,
+    /// Offset of the end of the element. Elements of unknown size extend to the
+    /// end of their parent.
+    pub end: u64,
+    /// Whether the size was given as unknown
+    pub unknown_size: bool,
+}
+
+/// Read the header of the element at `offset`, if there is one before `end`.
+pub(crate) fn read_header<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    end: u64,
+) -> io::Result<Option<ElementHeader>> {
+    if offset + 2 > end {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+    let (id, id_len) = read_vint(reader, false)?;
+    let (size, size_len) = read_vint(reader, true)?;
+    let start = offset + id_len + size_len;
+    let unknown_size = size == u64::MAX;
+    let element_end = if unknown_size {
+        end
+    } else {
+        start.saturating_add(size)
+    };
+    Ok(Some(ElementHeader {
+        id: u32::try_from(id).map_err(|_| invalid_data("invalid element ID"))?,
+        start,
+        end: element_end,
+        unknown_size,
+    }))
+}
+
+/// Find the first element with `id` between `start` and `end`.
+pub(crate) fn find<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    id: u32,
+) -> io::Result<Option<ElementHeader>> {
+    let mut offset = start;
+    while let Some(header) = read_header(reader, offset, end)? {
+        if header.id == id {
+            return Ok(Some(header));
+        }
+        if header.unknown_size {
+            return Ok(None);
+        }
+        offset = header.end;
+    }
+    Ok(None)
+}
+
+/// Read a variable length integer, returning it and its length in bytes.
+///
+/// Element IDs keep their length marker bits, sizes don't. A size with all its
+/// bits set is unknown, and returned as `u64::MAX`.
+pub(crate) fn read_vint<R: Read>(reader: &mut R, is_size: bool) -> io::Result<(u64, u64)> {
+    let mut first = [0; 1];
+    reader.read_exact(&mut first)?;
+    let len = first[0].leading_zeros() + 1;
+    if len > 8 {
+        return Err(invalid_data("invalid variable length integer"));
+    }
+    let mut value = u64::from(first[0]);
+    if is_size {
+        value &= 0xFF >> len;
+    }
+    let mut all_ones = value == 0xFF >> len;
+    for _ in 1..len {
+        reader.read_exact(&mut first)?;
+        all_ones &= first[0] == 0xFF;
+        value = (value << 8) | u64::from(first[0]);
+    }
+    if is_size && all_ones {
+        value = u64::MAX;
+    }
+    Ok((value, u64::from(len)))
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_header() {
+        // Segment of unknown size holding a timestamp element
+        let data = [
+            0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE7, 0x82,
+            0x01, 0x00,
+        ];
+        let mut reader = Cursor::new(&data[..]);
+        let segment = read_header(&mut reader, 0, 16).unwrap().unwrap();
+        assert_eq!(segment.id, SEGMENT);
+        assert!(segment.unknown_size);
+        assert_eq!((segment.start, segment.end), (12, 16));
+
+        let timestamp = find(&mut reader, segment.start, segment.end, 0xE7)
+            .unwrap()
+            .unwrap();
+        assert_eq!((timestamp.start, timestamp.end), (14, 16));
+        assert_eq!(
+            find(&mut reader, segment.start, segment.end, CLUSTER).unwrap(),
+            None
+        );
+    }
+}
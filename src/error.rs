@@ -0,0 +1,201 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use matroska::MatroskaError;
+
+/// Errors that can occur while determining creation dates and renaming files.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading a file failed
+    Io(io::Error),
+    /// Renaming a file failed
+    Rename(PathBuf, io::Error),
+    /// Copying a file failed
+    Copy(PathBuf, io::Error),
+    /// Linking to a file failed
+    Link(PathBuf, io::Error),
+    /// Setting the times of a file failed
+    Touch(PathBuf, io::Error),
+    /// The file is not in a supported format
+    UnsupportedFormat,
+    /// The Matroska file could not be parsed
+    Matroska(MatroskaError),
+    /// The MPEG4 file could not be parsed
+    Mp4(mp4::Error),
+    /// None of the date sources in the file held a creation date
+    NoCreationDate,
+    /// The destination of a rename already exists
+    RenameConflict(PathBuf),
+    /// The offset to apply to timestamps is too large
+    OffsetOutOfRange,
+    /// A file name template could not be parsed
+    InvalidTemplate(String),
+    /// A journal could not be read or written
+    InvalidJournal(PathBuf, serde_json::Error),
+    /// A file recorded in a journal no longer has the name it was given
+    FileMoved(PathBuf),
+    /// A command line option has a value that is out of range
+    InvalidOption(String),
+    /// A rules file could not be parsed
+    InvalidConfig(PathBuf, String),
+    /// The audio of a file could not be decoded
+    UnsupportedAudio(String),
+    /// The audio of two clips could not be lined up
+    AudioMismatch,
+    /// An index of files for finding duplicates could not be read or written
+    InvalidIndex(PathBuf, serde_json::Error),
+    /// The creation date could not be written into a file
+    WriteMetadata(String),
+}
+
+impl Error {
+    /// The process exit code for this kind of error.
+    ///
+    /// Usage errors exit with code 2, so codes start at 3.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Io(_)
+            | Error::Rename(_, _)
+            | Error::Copy(_, _)
+            | Error::Link(_, _)
+            | Error::Touch(_, _) => 3,
+            Error::UnsupportedFormat => 4,
+            Error::Matroska(_) | Error::Mp4(_) => 5,
+            Error::NoCreationDate => 6,
+            Error::RenameConflict(_) => 7,
+            Error::OffsetOutOfRange => 8,
+            Error::InvalidTemplate(_) | Error::InvalidOption(_) | Error::InvalidConfig(_, _) => 2,
+            Error::InvalidJournal(_, _) => 9,
+            Error::FileMoved(_) => 10,
+            Error::UnsupportedAudio(_) => 11,
+            Error::AudioMismatch => 12,
+            Error::InvalidIndex(_, _) => 13,
+            Error::WriteMetadata(_) => 14,
+        }
+    }
+
+    /// A short name for the kind of error, as used in reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::Rename(_, _) => "rename",
+            Error::Copy(_, _) => "copy",
+            Error::Link(_, _) => "link",
+            Error::Touch(_, _) => "touch",
+            Error::UnsupportedFormat => "unsupported-format",
+            Error::Matroska(_) => "matroska",
+            Error::Mp4(_) => "mp4",
+            Error::NoCreationDate => "no-creation-date",
+            Error::RenameConflict(_) => "rename-conflict",
+            Error::OffsetOutOfRange => "offset-out-of-range",
+            Error::InvalidTemplate(_) => "invalid-template",
+            Error::InvalidJournal(_, _) => "invalid-journal",
+            Error::FileMoved(_) => "file-moved",
+            Error::InvalidOption(_) => "invalid-option",
+            Error::InvalidConfig(_, _) => "invalid-config",
+            Error::UnsupportedAudio(_) => "unsupported-audio",
+            Error::AudioMismatch => "audio-mismatch",
+            Error::InvalidIndex(_, _) => "invalid-index",
+            Error::WriteMetadata(_) => "write-metadata",
+        }
+    }
+
+    /// The error for `err` from reading the boxes of an MPEG4 file: a parse error
+    /// if the file is corrupt or truncated, otherwise an I/O error.
+    pub(crate) fn mp4(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+                Error::Mp4(mp4::Error::IoError(err))
+            }
+            _ => Error::Io(err),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Rename(path, err) => {
+                write!(f, "unable to rename to {}: {}", path.display(), err)
+            }
+            Error::Copy(path, err) => write!(f, "unable to copy to {}: {}", path.display(), err),
+            Error::Link(path, err) => write!(f, "unable to link to {}: {}", path.display(), err),
+            Error::Touch(path, err) => {
+                write!(f, "unable to set the times of {}: {}", path.display(), err)
+            }
+            Error::UnsupportedFormat => f.write_str("unknown file type"),
+            Error::Matroska(err) => write!(f, "unable to parse Matroska file: {}", err),
+            Error::Mp4(err) => write!(f, "unable to parse MPEG4 file: {}", err),
+            Error::NoCreationDate => f.write_str("unable to determine creation date"),
+            Error::RenameConflict(path) => write!(f, "{} already exists", path.display()),
+            Error::OffsetOutOfRange => f.write_str("offset too big"),
+            Error::InvalidTemplate(msg) => write!(f, "invalid template: {}", msg),
+            Error::InvalidJournal(path, err) => {
+                write!(f, "invalid journal {}: {}", path.display(), err)
+            }
+            Error::FileMoved(path) => write!(f, "{} no longer exists", path.display()),
+            Error::InvalidOption(msg) => f.write_str(msg),
+            Error::InvalidConfig(path, msg) => {
+                write!(f, "invalid rules file {}: {}", path.display(), msg)
+            }
+            Error::UnsupportedAudio(msg) => write!(f, "unable to decode audio: {}", msg),
+            Error::AudioMismatch => f.write_str(
+                "no match found for the audio where the clips overlap by their creation dates, \
+                 or in their first 5 minutes",
+            ),
+            Error::InvalidIndex(path, err) => {
+                write!(f, "invalid index {}: {}", path.display(), err)
+            }
+            Error::WriteMetadata(msg) => write!(f, "unable to write the creation date: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err)
+            | Error::Rename(_, err)
+            | Error::Copy(_, err)
+            | Error::Link(_, err)
+            | Error::Touch(_, err) => Some(err),
+            Error::Matroska(err) => Some(err),
+            Error::Mp4(err) => Some(err),
+            Error::InvalidJournal(_, err) | Error::InvalidIndex(_, err) => Some(err),
+            Error::UnsupportedFormat
+            | Error::NoCreationDate
+            | Error::RenameConflict(_)
+            | Error::OffsetOutOfRange
+            | Error::InvalidTemplate(_)
+            | Error::FileMoved(_)
+            | Error::InvalidOption(_)
+            | Error::InvalidConfig(_, _)
+            | Error::UnsupportedAudio(_)
+            | Error::AudioMismatch
+            | Error::WriteMetadata(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<MatroskaError> for Error {
+    fn from(err: MatroskaError) -> Self {
+        Error::Matroska(err)
+    }
+}
+
+impl From<mp4::Error> for Error {
+    fn from(err: mp4::Error) -> Self {
+        match err {
+            mp4::Error::IoError(err) => Error::mp4(err),
+            err => Error::Mp4(err),
+        }
+    }
+}
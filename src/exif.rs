@@ -0,0 +1,485 @@
+//! Reading EXIF metadata from JPEG, HEIF and TIFF based (including many RAW)
+//! files.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use time::macros::format_description;
+use time::{Duration, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+use crate::{bmff, Clock};
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+
+/// IFDs with more entries than this are assumed to be corrupt.
+const MAX_IFD_ENTRIES: u16 = 1000;
+
+/// The EXIF fields of interest.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Exif {
+    /// `DateTimeOriginal` combined with `SubSecTimeOriginal` and
+    /// `OffsetTimeOriginal`. Without an offset the time is local, and held as UTC.
+    pub date_time_original: Option<(OffsetDateTime, Clock)>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+}
+
+/// Read the EXIF data from the APP1 segment of a JPEG file.
+pub(crate) fn read_jpeg<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Exif>> {
+    match find_app1(reader, b"Exif\0\0")? {
+        Some((start, _)) => read_tiff(reader, start),
+        None => Ok(None),
+    }
+}
+
+/// Find the first APP1 segment of a JPEG file that starts with `signature`.
+///
+/// Returns the offsets of the end of the signature and the end of the segment.
+pub(crate) fn find_app1<R: Read + Seek>(
+    reader: &mut R,
+    signature: &[u8],
+) -> io::Result<Option<(u64, u64)>> {
+    match scan_segments(reader, signature) {
+        Ok(segment) => Ok(segment),
+        // A truncated file without the segment is treated as not having one
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn scan_segments<R: Read + Seek>(
+    reader: &mut R,
+    signature: &[u8],
+) -> io::Result<Option<(u64, u64)>> {
+    reader.seek(SeekFrom::Start(0))?;
+    if bmff::read_u16(reader)? != 0xFFD8 {
+        return Ok(None);
+    }
+
+    loop {
+        let mut marker = bmff::read_u8(reader)?;
+        if marker != 0xFF {
+            return Ok(None);
+        }
+        // Markers may be preceded by any number of fill bytes
+        while marker == 0xFF {
+            marker = bmff::read_u8(reader)?;
+        }
+        match marker {
+            // Start of scan or end of image, the metadata segments come before these
+            0xDA | 0xD9 => return Ok(None),
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+
+        let length = u64::from(bmff::read_u16(reader)?);
+        let start = reader.stream_position()?;
+        let end = start + length.saturating_sub(2);
+        if marker == 0xE1 && end - start >= signature.len() as u64 {
+            let mut buf = vec![0; signature.len()];
+            reader.read_exact(&mut buf)?;
+            if buf == signature {
+                return Ok(Some((start + buf.len() as u64, end)));
+            }
+        }
+        reader.seek(SeekFrom::Start(end))?;
+    }
+}
+
+/// Read the EXIF data from the `Exif` item of a HEIF file.
+pub(crate) fn read_heif<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Exif>> {
+    let meta = match bmff::find_path(reader, &[b"meta"])? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+    // meta is a full box, skip the version and flags
+    let start = meta.start + 4;
+
+    let item_id = match find_exif_item(reader, start, meta.end)? {
+        Some(item_id) => item_id,
+        None => return Ok(None),
+    };
+    let offset = match find_item_offset(reader, start, meta.end, item_id)? {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+
+    // The item starts with the offset of the TIFF header from the end of this field
+    reader.seek(SeekFrom::Start(offset))?;
+    let tiff_header_offset = u64::from(bmff::read_u32(reader)?);
+    read_tiff(reader, offset + 4 + tiff_header_offset)
+}
+
+/// Find the ID of the `Exif` item in the `iinf` box.
+fn find_exif_item<R: Read + Seek>(reader: &mut R, start: u64, end: u64) -> io::Result<Option<u32>> {
+    let iinf = match bmff::find(reader, start, end, b"iinf")? {
+        Some(iinf) => iinf,
+        None => return Ok(None),
+    };
+    reader.seek(SeekFrom::Start(iinf.start))?;
+    let version = bmff::read_u8(reader)?;
+    let entries_start = iinf.start + if version == 0 { 6 } else { 8 };
+
+    let mut offset = entries_start;
+    while let Some(infe) = bmff::read_header(reader, offset, iinf.end)? {
+        offset = infe.end;
+        if &infe.box_type != b"infe" {
+            continue;
+        }
+        reader.seek(SeekFrom::Start(infe.start))?;
+        let version = bmff::read_u8(reader)?;
+        reader.seek(SeekFrom::Current(3))?;
+        // Item types were added in version 2
+        let item_id = match version {
+            2 => u32::from(bmff::read_u16(reader)?),
+            3 => bmff::read_u32(reader)?,
+            _ => continue,
+        };
+        let _protection_index = bmff::read_u16(reader)?;
+        let mut item_type = [0; 4];
+        reader.read_exact(&mut item_type)?;
+        if &item_type == b"Exif" {
+            return Ok(Some(item_id));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the file offset of the first extent of item `item_id` in the `iloc` box.
+fn find_item_offset<R: Read + Seek>(
+    reader: &mut R,
+    start: u64,
+    end: u64,
+    item_id: u32,
+) -> io::Result<Option<u64>> {
+    let iloc = match bmff::find(reader, start, end, b"iloc")? {
+        Some(iloc) => iloc,
+        None => return Ok(None),
+    };
+    reader.seek(SeekFrom::Start(iloc.start))?;
+    let version = bmff::read_u8(reader)?;
+    reader.seek(SeekFrom::Current(3))?;
+    let sizes = bmff::read_u16(reader)?;
+    let offset_size = (sizes >> 12) as u8;
+    let length_size = ((sizes >> 8) & 0xF) as u8;
+    let base_offset_size = ((sizes >> 4) & 0xF) as u8;
+    let index_size = if version == 1 || version == 2 {
+        (sizes & 0xF) as u8
+    } else {
+        0
+    };
+    let item_count = if version < 2 {
+        u32::from(bmff::read_u16(reader)?)
+    } else {
+        bmff::read_u32(reader)?
+    };
+
+    for _ in 0..item_count {
+        let id = if version < 2 {
+            u32::from(bmff::read_u16(reader)?)
+        } else {
+            bmff::read_u32(reader)?
+        };
+        let construction_method = if version == 1 || version == 2 {
+            bmff::read_u16(reader)? & 0xF
+        } else {
+            0
+        };
+        let _data_reference_index = bmff::read_u16(reader)?;
+        let base_offset = bmff::read_uint(reader, base_offset_size)?;
+        let extent_count = bmff::read_u16(reader)?;
+        for extent in 0..extent_count {
+            let _extent_index = bmff::read_uint(reader, index_size)?;
+            let extent_offset = bmff::read_uint(reader, offset_size)?;
+            let _extent_length = bmff::read_uint(reader, length_size)?;
+            // Only items stored at an offset in the file are supported
+            if id == item_id && extent == 0 && construction_method == 0 {
+                return base_offset
+                    .checked_add(extent_offset)
+                    .map(Some)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "item offset out of range")
+                    });
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Read the EXIF data from a TIFF structure starting at `base`.
+///
+/// TIFF based RAW formats (DNG, CR2, NEF, ARW, etc.) are TIFF files, so
+/// `base` is 0 for those.
+pub(crate) fn read_tiff<R: Read + Seek>(reader: &mut R, base: u64) -> io::Result<Option<Exif>> {
+    match Tiff::new(reader, base).and_then(|tiff| tiff.map(Tiff::read_exif).transpose()) {
+        Ok(exif) => Ok(exif),
+        // Truncated or corrupt EXIF data is treated as missing
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+struct Tiff<'a, R> {
+    reader: &'a mut R,
+    base: u64,
+    big_endian: bool,
+}
+
+struct IfdEntry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    /// The value if it fits in 4 bytes, otherwise its offset from the TIFF header
+    value: [u8; 4],
+}
+
+impl<'a, R: Read + Seek> Tiff<'a, R> {
+    fn new(reader: &'a mut R, base: u64) -> io::Result<Option<Self>> {
+        reader.seek(SeekFrom::Start(base))?;
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let big_endian = match &header {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return Ok(None),
+        };
+        Ok(Some(Tiff {
+            reader,
+            base,
+            big_endian,
+        }))
+    }
+
+    fn read_exif(mut self) -> io::Result<Exif> {
+        let mut exif = Exif::default();
+
+        self.reader.seek(SeekFrom::Start(self.base + 4))?;
+        let ifd0_offset = self.read_u32()?;
+        let ifd0 = self.read_ifd(ifd0_offset)?;
+        let mut exif_ifd_offset = None;
+        for entry in &ifd0 {
+            match entry.tag {
+                TAG_MAKE => exif.make = self.read_string(entry)?,
+                TAG_MODEL => exif.model = self.read_string(entry)?,
+                TAG_EXIF_IFD if entry.field_type == TYPE_LONG => {
+                    exif_ifd_offset = Some(self.u32_from(entry.value))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(offset) = exif_ifd_offset {
+            let (mut date_time, mut sub_sec, mut offset_time) = (None, None, None);
+            for entry in &self.read_ifd(offset)? {
+                match entry.tag {
+                    TAG_DATE_TIME_ORIGINAL => date_time = self.read_string(entry)?,
+                    TAG_SUB_SEC_TIME_ORIGINAL => sub_sec = self.read_string(entry)?,
+                    TAG_OFFSET_TIME_ORIGINAL => offset_time = self.read_string(entry)?,
+                    _ => {}
+                }
+            }
+            exif.date_time_original = date_time.and_then(|date_time| {
+                parse_date_time(&date_time, sub_sec.as_deref(), offset_time.as_deref())
+            });
+        }
+
+        Ok(exif)
+    }
+
+    fn read_ifd(&mut self, offset: u32) -> io::Result<Vec<IfdEntry>> {
+        self.reader
+            .seek(SeekFrom::Start(self.base + u64::from(offset)))?;
+        let count = self.read_u16()?;
+        if count > MAX_IFD_ENTRIES {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::with_capacity(usize::from(count));
+        for _ in 0..count {
+            let tag = self.read_u16()?;
+            let field_type = self.read_u16()?;
+            let count = self.read_u32()?;
+            let mut value = [0; 4];
+            self.reader.read_exact(&mut value)?;
+            entries.push(IfdEntry {
+                tag,
+                field_type,
+                count,
+                value,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn read_string(&mut self, entry: &IfdEntry) -> io::Result<Option<String>> {
+        if entry.field_type != TYPE_ASCII {
+            return Ok(None);
+        }
+        let len = entry.count as usize;
+        let bytes = if len <= 4 {
+            entry.value[..len].to_vec()
+        } else {
+            let offset = self.u32_from(entry.value);
+            self.reader
+                .seek(SeekFrom::Start(self.base + u64::from(offset)))?;
+            let mut bytes = Vec::new();
+            self.reader
+                .by_ref()
+                .take(len as u64)
+                .read_to_end(&mut bytes)?;
+            bytes
+        };
+
+        // Strings are NUL terminated, and often padded with spaces
+        let s = String::from_utf8_lossy(&bytes);
+        let s = s.trim_end_matches('\0').trim();
+        Ok((!s.is_empty()).then(|| String::from(s)))
+    }
+
+    fn read_u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.reader.read_exact(&mut buf)?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(buf)
+        } else {
+            u16::from_le_bytes(buf)
+        })
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.reader.read_exact(&mut buf)?;
+        Ok(self.u32_from(buf))
+    }
+
+    fn u32_from(&self, buf: [u8; 4]) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(buf)
+        } else {
+            u32::from_le_bytes(buf)
+        }
+    }
+}
+
+/// Parse an EXIF date (`YYYY:MM:DD HH:MM:SS`) along with its optional sub-second
+/// digits and offset (`+HH:MM`).
+fn parse_date_time(
+    date_time: &str,
+    sub_sec: Option<&str>,
+    offset: Option<&str>,
+) -> Option<(OffsetDateTime, Clock)> {
+    let date_time = PrimitiveDateTime::parse(
+        date_time,
+        format_description!("[year]:[month]:[day] [hour]:[minute]:[second]"),
+    )
+    .ok()?;
+    let date_time = match sub_sec.filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+    {
+        // The digits are a decimal fraction of a second
+        Some(digits) => {
+            let digits = &digits[..digits.len().min(9)];
+            let nanos = digits.parse::<i64>().ok()? * 10_i64.pow(9 - digits.len() as u32);
+            date_time + Duration::nanoseconds(nanos)
+        }
+        None => date_time,
+    };
+    let offset = offset.and_then(|offset| {
+        UtcOffset::parse(offset, format_description!("[offset_hour]:[offset_minute]")).ok()
+    });
+    Some(match offset {
+        Some(offset) => (date_time.assume_offset(offset), Clock::Absolute),
+        None => (date_time.assume_utc(), Clock::Local),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use time::macros::datetime;
+
+    /// A little endian TIFF header with IFD0 holding Make and a pointer to an EXIF
+    /// IFD holding the original date.
+    fn tiff() -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"II*\0");
+        data.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0 at 8: 2 entries
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&TAG_MAKE.to_le_bytes());
+        data.extend_from_slice(&TYPE_ASCII.to_le_bytes());
+        data.extend_from_slice(&4u32.to_le_bytes());
+        data.extend_from_slice(b"Sony");
+        data.extend_from_slice(&TAG_EXIF_IFD.to_le_bytes());
+        data.extend_from_slice(&TYPE_LONG.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&38u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        // EXIF IFD at 38: 3 entries, values from 76
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(&TAG_DATE_TIME_ORIGINAL.to_le_bytes());
+        data.extend_from_slice(&TYPE_ASCII.to_le_bytes());
+        data.extend_from_slice(&20u32.to_le_bytes());
+        data.extend_from_slice(&80u32.to_le_bytes());
+        data.extend_from_slice(&TAG_OFFSET_TIME_ORIGINAL.to_le_bytes());
+        data.extend_from_slice(&TYPE_ASCII.to_le_bytes());
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.extend_from_slice(&100u32.to_le_bytes());
+        data.extend_from_slice(&TAG_SUB_SEC_TIME_ORIGINAL.to_le_bytes());
+        data.extend_from_slice(&TYPE_ASCII.to_le_bytes());
+        data.extend_from_slice(&4u32.to_le_bytes());
+        data.extend_from_slice(b"25\0\0");
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.resize(80, 0);
+        data.extend_from_slice(b"2023:04:12 14:39:01\0");
+        data.extend_from_slice(b"+10:00\0");
+        data
+    }
+
+    #[test]
+    fn test_read_tiff() {
+        let exif = read_tiff(&mut Cursor::new(tiff()), 0).unwrap().unwrap();
+        assert_eq!(exif.make.as_deref(), Some("Sony"));
+        assert_eq!(exif.model, None);
+        assert_eq!(
+            exif.date_time_original,
+            Some((datetime!(2023-04-12 14:39:01.25 +10:00), Clock::Absolute))
+        );
+    }
+
+    #[test]
+    fn test_read_jpeg() {
+        let tiff = tiff();
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0, 0xFF, 0xE1];
+        data.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        data.extend_from_slice(b"Exif\0\0");
+        data.extend_from_slice(&tiff);
+        data.extend_from_slice(&[0xFF, 0xD9]);
+        let exif = read_jpeg(&mut Cursor::new(data)).unwrap().unwrap();
+        assert_eq!(exif.make.as_deref(), Some("Sony"));
+    }
+
+    #[test]
+    fn test_read_truncated_jpeg() {
+        // The file ends in the middle of a segment that isn't EXIF
+        let data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 16, 0, 0];
+        assert_eq!(read_jpeg(&mut Cursor::new(data)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_date_time_without_offset() {
+        assert_eq!(
+            parse_date_time("2023:04:12 14:39:01", None, None),
+            Some((datetime!(2023-04-12 14:39:01 UTC), Clock::Local))
+        );
+        assert_eq!(parse_date_time("0000:00:00 00:00:00", None, None), None);
+    }
+}
@@ -0,0 +1,197 @@
+//! Capture dates in file names.
+//!
+//! Many phones, cameras and apps name files after the time they were recorded,
+//! and these names often survive when the metadata inside the file doesn't. The
+//! [`PATTERNS`] recognised are:
+//!
+//! | Pattern   | Example                                              |
+//! |-----------|------------------------------------------------------|
+//! | `pixel`   | `PXL_20230412_143901123.mp4`                         |
+//! | `compact` | `VID_20230412_143901.mp4`                            |
+//! | `at`      | `WhatsApp Video 2023-04-12 at 14.39.01.mp4`          |
+//! | `dropbox` | `2023-04-12 14.39.01.mov`                            |
+//! | `obs`     | `2023-04-12 14-39-01.mkv`                            |
+//! | `date`    | `VID-20230412-WA0001.mp4`, `2023-04-12 GX010123.MP4` |
+//!
+//! GoPro camera names (`GX010123.MP4`) carry no date of their own, so only a date
+//! added to the name alongside them, e.g. by an import tool, is found.
+
+use time::format_description::BorrowedFormatItem;
+use time::macros::format_description;
+use time::parsing::Parsed;
+use time::{Date, Duration, OffsetDateTime, PrimitiveDateTime, Time};
+
+/// A date format that appears in file names.
+#[derive(Debug, Clone, Copy)]
+pub struct FilenamePattern {
+    /// A short name for the pattern
+    pub name: &'static str,
+    /// Whether the pattern includes the time of day, or just the date
+    pub has_time: bool,
+    format: &'static [BorrowedFormatItem<'static>],
+}
+
+/// A date found in a file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilenameDate {
+    /// The date in the file name. File names don't include an offset, so the time
+    /// is a local time, held as UTC. Dates without a time are at midnight.
+    pub datetime: OffsetDateTime,
+    /// The name of the pattern that matched
+    pub pattern: &'static str,
+    /// Whether the file name included the time of day, or just the date
+    pub has_time: bool,
+}
+
+/// The patterns tried, in order. More specific patterns come first so that, for
+/// example, the milliseconds of a Pixel name aren't mistaken for another number.
+pub const PATTERNS: &[FilenamePattern] = &[
+    FilenamePattern {
+        name: "pixel",
+        has_time: true,
+        format: format_description!(
+            "[year][month][day]_[hour][minute][second][subsecond digits:3]"
+        ),
+    },
+    FilenamePattern {
+        name: "compact",
+        has_time: true,
+        format: format_description!("[year][month][day][first [_] [-]][hour][minute][second]"),
+    },
+    FilenamePattern {
+        name: "at",
+        has_time: true,
+        format: format_description!("[year]-[month]-[day] at [hour].[minute].[second]"),
+    },
+    FilenamePattern {
+        name: "dropbox",
+        has_time: true,
+        format: format_description!("[year]-[month]-[day] [hour].[minute].[second]"),
+    },
+    FilenamePattern {
+        name: "obs",
+        has_time: true,
+        format: format_description!("[year]-[month]-[day][first [ ] [_]][hour]-[minute]-[second]"),
+    },
+    FilenamePattern {
+        name: "date",
+        has_time: false,
+        format: format_description!("[first [[year]-[month]-[day]] [[year][month][day]]]"),
+    },
+];
+
+/// Find the first date in `name` matching one of the [`PATTERNS`].
+///
+/// Dates must not be part of a longer run of digits, so `IMG_120230412.jpg`
+/// doesn't contain a date.
+pub fn parse_date(name: &str) -> Option<FilenameDate> {
+    PATTERNS.iter().find_map(|pattern| pattern.find(name))
+}
+
+impl FilenamePattern {
+    /// Find the first date in `name` matching this pattern.
+    pub fn find(&self, name: &str) -> Option<FilenameDate> {
+        let bytes = name.as_bytes();
+        (0..bytes.len())
+            .filter(|&i| bytes[i].is_ascii_digit() && (i == 0 || !bytes[i - 1].is_ascii_digit()))
+            .find_map(|i| self.parse_at(&bytes[i..]))
+    }
+
+    fn parse_at(&self, input: &[u8]) -> Option<FilenameDate> {
+        let mut parsed = Parsed::new();
+        let rest = parsed.parse_items(input, self.format).ok()?;
+        if rest.first().is_some_and(u8::is_ascii_digit) {
+            return None;
+        }
+        let datetime = if self.has_time {
+            PrimitiveDateTime::try_from(parsed).ok()?
+        } else {
+            PrimitiveDateTime::new(Date::try_from(parsed).ok()?, Time::MIDNIGHT)
+        };
+        Some(FilenameDate {
+            datetime: datetime.assume_utc(),
+            pattern: self.name,
+            has_time: self.has_time,
+        })
+    }
+}
+
+impl FilenameDate {
+    /// Whether `datetime` could be the time in the file name, in some timezone.
+    ///
+    /// The timezone of the file name is unknown, so the two may differ by up to 14
+    /// hours, in steps of 15 minutes (allowing a couple of minutes either way).
+    /// Dates without a time agree with any time on that day, in some timezone.
+    pub fn agrees_with(&self, datetime: OffsetDateTime) -> bool {
+        let max_offset = Duration::hours(14);
+        let difference = self.datetime - datetime;
+        if self.has_time {
+            let slack = Duration::minutes(2);
+            let step = Duration::minutes(15).whole_seconds();
+            let off_step = (difference.whole_seconds() + slack.whole_seconds()).rem_euclid(step);
+            difference.abs() <= max_offset + slack && off_step <= 2 * slack.whole_seconds()
+        } else {
+            // The date is the start of the day that datetime falls in
+            difference <= max_offset && difference > -(Duration::DAY + max_offset)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_parse_date() {
+        let parse = |name| parse_date(name).map(|date| (date.pattern, date.datetime));
+        let expected = datetime!(2023-04-12 14:39:01 UTC);
+        assert_eq!(
+            parse("VID_20230412_143901.mp4"),
+            Some(("compact", expected))
+        );
+        assert_eq!(parse("20230412-143901.mov"), Some(("compact", expected)));
+        assert_eq!(
+            parse("PXL_20230412_143901123.mp4"),
+            Some(("pixel", expected + Duration::milliseconds(123)))
+        );
+        assert_eq!(
+            parse("WhatsApp Video 2023-04-12 at 14.39.01.mp4"),
+            Some(("at", expected))
+        );
+        assert_eq!(
+            parse("2023-04-12 14.39.01.mov"),
+            Some(("dropbox", expected))
+        );
+        assert_eq!(parse("2023-04-12 14-39-01.mkv"), Some(("obs", expected)));
+        assert_eq!(
+            parse("VID-20230412-WA0001.mp4"),
+            Some(("date", datetime!(2023-04-12 0:00 UTC)))
+        );
+        assert_eq!(
+            parse("2023-04-12 GX010123.MP4"),
+            Some(("date", datetime!(2023-04-12 0:00 UTC)))
+        );
+
+        assert_eq!(parse("IMG_4792.mov"), None);
+        assert_eq!(parse("GX010123.MP4"), None);
+        assert_eq!(parse("VID_20231312_143901.mp4"), None);
+        assert_eq!(parse("IMG_120230412.jpg"), None);
+    }
+
+    #[test]
+    fn test_agrees_with() {
+        let named = parse_date("VID_20230412_143901.mp4").unwrap();
+        assert!(named.agrees_with(datetime!(2023-04-12 14:39:01 UTC)));
+        assert!(named.agrees_with(datetime!(2023-04-12 14:39:03 +10:00)));
+        assert!(named.agrees_with(datetime!(2023-04-12 14:38:59 -05:30)));
+        assert!(!named.agrees_with(datetime!(2023-04-12 14:39:01 +15:00)));
+        assert!(!named.agrees_with(datetime!(2023-04-12 14:20:01 UTC)));
+        assert!(!named.agrees_with(datetime!(2022-04-12 14:39:01 UTC)));
+
+        let named = parse_date("VID-20230412-WA0001.mp4").unwrap();
+        assert!(named.agrees_with(datetime!(2023-04-12 23:59:59 +10:00)));
+        assert!(named.agrees_with(datetime!(2023-04-12 0:00 UTC)));
+        assert!(!named.agrees_with(datetime!(2023-04-14 0:00 UTC)));
+    }
+}
@@ -0,0 +1,167 @@
+//! A record of renames, so that they can be undone.
+//!
+//! The journal is a JSON Lines file with one [`JournalEntry`] per rename.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::rename::{self, Mode};
+use crate::{DateSource, Error};
+
+/// The name of the journal written next to renamed files when no other location
+/// is chosen.
+pub const DEFAULT_JOURNAL_NAME: &str = ".mkv-rename-journal.jsonl";
+
+/// A single rename.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// The absolute path of the file before it was renamed
+    pub old_path: PathBuf,
+    /// The absolute path of the file after it was renamed
+    pub new_path: PathBuf,
+    /// When the rename happened
+    #[serde(with = "time::serde::rfc3339")]
+    pub renamed_at: OffsetDateTime,
+    /// The creation date the new name is based on, with the offset applied
+    #[serde(with = "time::serde::rfc3339")]
+    pub creation_date: OffsetDateTime,
+    /// Where the creation date came from
+    pub source: DateSource,
+    /// Whether the creation date is only a guess, e.g. from the file system
+    #[serde(default)]
+    pub low_confidence: bool,
+    /// The offset applied to the creation date, in seconds
+    pub offset: i64,
+    /// How the file was put at its new path
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+/// The journal used for renames in `dir` when no other location is chosen.
+pub fn default_journal_path(dir: &Path) -> PathBuf {
+    dir.join(DEFAULT_JOURNAL_NAME)
+}
+
+/// Append `entry` to the journal at `path`, creating it if necessary.
+pub fn append(path: &Path, entry: &JournalEntry) -> Result<(), Error> {
+    let mut line =
+        serde_json::to_vec(entry).map_err(|err| Error::InvalidJournal(path.to_path_buf(), err))?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Read all the entries in the journal at `path`, oldest first.
+pub fn read(path: &Path) -> Result<Vec<JournalEntry>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|err| Error::InvalidJournal(path.to_path_buf(), err))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Replace the contents of the journal at `path` with `entries`.
+///
+/// The journal is removed if there are no entries left.
+pub fn write(path: &Path, entries: &[JournalEntry]) -> Result<(), Error> {
+    if entries.is_empty() {
+        fs::remove_file(path)?;
+        return Ok(());
+    }
+
+    let mut contents = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut contents, entry)
+            .map_err(|err| Error::InvalidJournal(path.to_path_buf(), err))?;
+        contents.push(b'\n');
+    }
+
+    // Write to a temporary file and rename it over the journal so that it's never
+    // left half written
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&contents)?;
+    file.sync_data()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reverse the rename recorded in `entry`.
+///
+/// Files that no longer have the name they were renamed to are left alone, as is
+/// anything already at the original path. Copies and links are removed, as long
+/// as the original is still there.
+pub fn undo(entry: &JournalEntry, dry_run: bool) -> Result<(), Error> {
+    if fs::symlink_metadata(&entry.new_path).is_err() {
+        return Err(Error::FileMoved(entry.new_path.clone()));
+    }
+    if entry.mode.keeps_original() {
+        if fs::symlink_metadata(&entry.old_path).is_err() {
+            return Err(Error::FileMoved(entry.old_path.clone()));
+        }
+        if !dry_run {
+            fs::remove_file(&entry.new_path)?;
+        }
+        return Ok(());
+    }
+    if dry_run {
+        if fs::symlink_metadata(&entry.old_path).is_ok() {
+            return Err(Error::RenameConflict(entry.old_path.clone()));
+        }
+        Ok(())
+    } else {
+        // Moves back across file systems are copied back
+        rename::transfer_noreplace(&entry.new_path, &entry.old_path, Mode::Move).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entry = JournalEntry {
+            old_path: PathBuf::from("/videos/IMG_4792.mov"),
+            new_path: PathBuf::from("/videos/1681265941 IMG_4792.mov"),
+            renamed_at: OffsetDateTime::from_unix_timestamp(1700000000).unwrap(),
+            creation_date: OffsetDateTime::from_unix_timestamp(1681265941).unwrap(),
+            source: DateSource::QuickTimeCreationDate,
+            low_confidence: false,
+            offset: 3600,
+            mode: Mode::Copy,
+        };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(r#""source":"quicktime-creationdate""#));
+        assert_eq!(serde_json::from_str::<JournalEntry>(&json).unwrap(), entry);
+
+        assert!(json.contains(r#""mode":"copy""#));
+        assert_eq!(serde_json::from_str::<JournalEntry>(&json).unwrap(), entry);
+
+        // Journals written before low_confidence and mode were recorded
+        let json = json
+            .replace(r#""low_confidence":false,"#, "")
+            .replace(r#","mode":"copy""#, "");
+        let entry = JournalEntry {
+            mode: Mode::Rename,
+            ..entry
+        };
+        assert_eq!(serde_json::from_str::<JournalEntry>(&json).unwrap(), entry);
+    }
+}
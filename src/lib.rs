@@ -0,0 +1,851 @@
+//! Read the creation date out of video and photo files.
+//!
+//! This is the library behind the `mkv-rename` tool. It can be used to detect
+//! the creation date of Matroska and MPEG4 videos, and JPEG, HEIF and TIFF based
+//! photos without going through the command line interface:
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use mkv_rename::CreationDateExtractor;
+//!
+//! let extractor = CreationDateExtractor::new();
+//! let metadata = extractor.extract_path(Path::new("IMG_4818.mkv")).unwrap();
+//! let date = metadata.creation_date;
+//! println!("{} (from {})", date.datetime, date.source);
+//! ```
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use matroska::{Matroska, TagValue};
+use mp4::MoovBox;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Iso8601;
+use time::macros::datetime;
+use time::{Duration, OffsetDateTime};
+
+pub mod align;
+pub mod audio;
+mod bmff;
+pub mod config;
+pub mod drift;
+pub mod duplicates;
+mod ebml;
+mod error;
+mod exif;
+pub mod filename;
+pub mod journal;
+pub mod manifest;
+pub mod patch;
+mod quicktime;
+pub mod rename;
+pub mod report;
+pub mod source;
+pub mod template;
+pub mod tz;
+pub mod walk;
+mod xmp;
+
+pub use error::Error;
+use source::SourceKind;
+
+/// The types of box that an ISO-BMFF file can start with. QuickTime files may
+/// start with padding (wide, free, skip) instead of ftyp.
+const BMFF_BOX_TYPES: [&[u8]; 6] = [b"ftyp", b"moov", b"mdat", b"wide", b"free", b"skip"];
+
+/// Seconds between the MP4 epoch (1904-01-01) and the Unix epoch (1970-01-01).
+const MP4_EPOCH_OFFSET: i64 = 2082844800;
+
+/// Creation dates before this are assumed to be bogus by default.
+pub const DEFAULT_EARLIEST_DATE: OffsetDateTime = datetime!(1990-01-01 0:00 UTC);
+
+/// The `ftyp` major brands of HEIF images.
+const HEIF_BRANDS: [&[u8]; 8] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"avif",
+];
+
+/// The container formats that creation dates can be extracted from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    /// Matroska (`mkv`)
+    Matroska,
+    /// MPEG4 and QuickTime (`mp4`, `m4v`, `mov`)
+    Mp4,
+    /// JPEG images (`jpg`, `jpeg`)
+    Jpeg,
+    /// HEIF images (`heic`, `heif`, `avif`)
+    Heif,
+    /// TIFF images, including TIFF based RAW formats (`tif`, `tiff`, `dng`, `cr2`,
+    /// `nef`, `arw`)
+    Tiff,
+}
+
+/// The container format of a file, as detected from its contents and its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Detection {
+    /// The container format to parse the file as
+    pub container: Container,
+    /// The container format implied by the file extension
+    pub from_extension: Option<Container>,
+}
+
+/// Where a creation date was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DateSource {
+    /// The `com.apple.quicktime.creationdate` tag
+    #[serde(rename = "quicktime-creationdate")]
+    QuickTimeCreationDate,
+    /// The `DateUTC` element of the Matroska segment info
+    #[serde(rename = "matroska-date-utc")]
+    MatroskaDateUtc,
+    /// The creation time in the MPEG4 movie header (`mvhd`)
+    #[serde(rename = "mp4-mvhd")]
+    Mp4MovieHeader,
+    /// The creation time in an MPEG4 track header (`tkhd`)
+    #[serde(rename = "mp4-tkhd")]
+    Mp4TrackHeader,
+    /// The creation time in an MPEG4 media header (`mdhd`)
+    #[serde(rename = "mp4-mdhd")]
+    Mp4MediaHeader,
+    /// The EXIF `DateTimeOriginal` tag, with `SubSecTimeOriginal` and
+    /// `OffsetTimeOriginal` if present
+    #[serde(rename = "exif-datetimeoriginal")]
+    ExifDateTimeOriginal,
+    /// A creation date property in embedded XMP metadata
+    #[serde(rename = "xmp")]
+    Xmp,
+    /// A date in the file name
+    #[serde(rename = "filename")]
+    Filename,
+    /// A creation date property in an XMP sidecar file
+    #[serde(rename = "xmp-sidecar")]
+    XmpSidecar,
+    /// The birth (creation) time of the file, where the file system records it
+    #[serde(rename = "file-btime")]
+    FileBirthTime,
+    /// The modification time of the file
+    #[serde(rename = "file-mtime")]
+    FileModified,
+}
+
+/// Whether a creation date is a point in time, or the reading of a clock in an
+/// unknown timezone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Clock {
+    /// A time in UTC, or with its offset from UTC: QuickTime tags, Matroska
+    /// `DateUTC`, EXIF and XMP dates with an offset, and file system times
+    Absolute,
+    /// A local time without an offset: MPEG4 header times, file names, and EXIF
+    /// and XMP dates without an offset. These are held as if they were UTC, and
+    /// are what time zones and offsets are applied to.
+    Local,
+}
+
+/// A creation date and where it came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreationDate {
+    pub datetime: OffsetDateTime,
+    pub source: DateSource,
+    pub clock: Clock,
+}
+
+/// Metadata read from a video or photo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub creation_date: CreationDate,
+    pub device: Device,
+    /// How long the video lasts, if it says. Photos have no duration.
+    pub duration: Option<Duration>,
+}
+
+/// The camera and software that produced a file, as far as its metadata says.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// The make of the camera that recorded the file
+    pub make: Option<String>,
+    /// The model of the camera that recorded the file
+    pub model: Option<String>,
+    /// The major brand in the `ftyp` box of an MPEG4 file, e.g. `qt` or `mp42`,
+    /// without trailing spaces
+    pub brand: Option<String>,
+    /// The encoder of an MPEG4 file (the `©too` tag)
+    pub encoder: Option<String>,
+    /// The `WritingApp` of a Matroska file
+    pub writing_app: Option<String>,
+    /// The `MuxingApp` of a Matroska file
+    pub muxing_app: Option<String>,
+}
+
+/// All the creation dates found for a file, before one is chosen, and its device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contents {
+    /// The dates in the order they are tried within each kind of source
+    pub dates: Vec<CreationDate>,
+    pub device: Device,
+    /// How long the video lasts, if it says
+    pub duration: Option<Duration>,
+}
+
+/// Extracts creation dates from video and photo files.
+///
+/// Dates are taken from the first kind of source, in priority order, that has a
+/// plausible date (see [`CreationDateExtractor::sources`]). Many cameras write
+/// zero, or otherwise bogus, creation dates. Dates before the earliest date (see
+/// [`CreationDateExtractor::earliest`]) or in the future are ignored in favour of
+/// the next source.
+#[derive(Debug, Clone)]
+pub struct CreationDateExtractor {
+    earliest: OffsetDateTime,
+    sources: Vec<SourceKind>,
+}
+
+impl Container {
+    /// Determine the container format of a file from its contents, falling back on
+    /// the extension of `path` when the contents are not recognised.
+    pub fn detect<R: Read + Seek>(reader: &mut R, path: &Path) -> Result<Detection, Error> {
+        let from_extension = Container::from_path(path);
+        let container = Container::sniff(reader)?
+            .or(from_extension)
+            .ok_or(Error::UnsupportedFormat)?;
+        Ok(Detection {
+            container,
+            from_extension,
+        })
+    }
+
+    /// Determine the container format from the magic bytes at the start of `reader`.
+    ///
+    /// The reader is rewound to the start afterwards.
+    pub fn sniff<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Container>> {
+        let mut header = Vec::with_capacity(12);
+        reader.by_ref().take(12).read_to_end(&mut header)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let box_type = header.get(4..8);
+        let brand = header.get(8..12);
+        let container = match header.as_slice() {
+            // EBML header
+            [0x1A, 0x45, 0xDF, 0xA3, ..] => Some(Container::Matroska),
+            [0xFF, 0xD8, 0xFF, ..] => Some(Container::Jpeg),
+            [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => Some(Container::Tiff),
+            _ if box_type == Some(b"ftyp") && brand.is_some_and(|b| HEIF_BRANDS.contains(&b)) => {
+                Some(Container::Heif)
+            }
+            _ if box_type.is_some_and(|t| BMFF_BOX_TYPES.contains(&t)) => Some(Container::Mp4),
+            _ => None,
+        };
+        Ok(container)
+    }
+
+    /// Determine the container format from the extension of `path`.
+    pub fn from_path(path: &Path) -> Option<Container> {
+        match path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("mkv") => Some(Container::Matroska),
+            Some("mov" | "mp4" | "m4v") => Some(Container::Mp4),
+            Some("jpg" | "jpeg") => Some(Container::Jpeg),
+            Some("heic" | "heif" | "avif") => Some(Container::Heif),
+            Some("tif" | "tiff" | "dng" | "cr2" | "nef" | "arw") => Some(Container::Tiff),
+            _ => None,
+        }
+    }
+}
+
+impl Detection {
+    /// The container format implied by the file extension, if it differs from the
+    /// one detected from the file contents.
+    pub fn extension_mismatch(&self) -> Option<Container> {
+        self.from_extension
+            .filter(|&container| container != self.container)
+    }
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Container::Matroska => "Matroska",
+            Container::Mp4 => "MPEG4",
+            Container::Jpeg => "JPEG",
+            Container::Heif => "HEIF",
+            Container::Tiff => "TIFF",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for DateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DateSource::QuickTimeCreationDate => "quicktime-creationdate",
+            DateSource::MatroskaDateUtc => "matroska-date-utc",
+            DateSource::Mp4MovieHeader => "mp4-mvhd",
+            DateSource::Mp4TrackHeader => "mp4-tkhd",
+            DateSource::Mp4MediaHeader => "mp4-mdhd",
+            DateSource::ExifDateTimeOriginal => "exif-datetimeoriginal",
+            DateSource::Xmp => "xmp",
+            DateSource::Filename => "filename",
+            DateSource::XmpSidecar => "xmp-sidecar",
+            DateSource::FileBirthTime => "file-btime",
+            DateSource::FileModified => "file-mtime",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clock::Absolute => f.write_str("absolute"),
+            Clock::Local => f.write_str("local"),
+        }
+    }
+}
+
+impl DateSource {
+    /// The kind of source this is, for ordering sources by priority.
+    pub fn kind(&self) -> SourceKind {
+        match self {
+            DateSource::QuickTimeCreationDate
+            | DateSource::MatroskaDateUtc
+            | DateSource::Mp4MovieHeader => SourceKind::Container,
+            DateSource::Mp4TrackHeader | DateSource::Mp4MediaHeader => SourceKind::Track,
+            DateSource::ExifDateTimeOriginal | DateSource::Xmp => SourceKind::Exif,
+            DateSource::Filename => SourceKind::Filename,
+            DateSource::XmpSidecar => SourceKind::Sidecar,
+            DateSource::FileBirthTime | DateSource::FileModified => SourceKind::Filesystem,
+        }
+    }
+
+    /// Whether dates from this source are only a guess at when the file was
+    /// recorded. File system times change when files are copied or edited.
+    pub fn is_low_confidence(&self) -> bool {
+        self.kind() == SourceKind::Filesystem
+    }
+}
+
+impl Default for CreationDateExtractor {
+    fn default() -> Self {
+        CreationDateExtractor {
+            earliest: DEFAULT_EARLIEST_DATE,
+            sources: source::DEFAULT_SOURCES.to_vec(),
+        }
+    }
+}
+
+impl CreationDateExtractor {
+    pub fn new() -> Self {
+        CreationDateExtractor::default()
+    }
+
+    /// Ignore creation dates before `earliest`. Defaults to [`DEFAULT_EARLIEST_DATE`].
+    pub fn earliest(mut self, earliest: OffsetDateTime) -> Self {
+        self.earliest = earliest;
+        self
+    }
+
+    /// Take dates from these kinds of source, in priority order. Kinds that are not
+    /// listed are not used. Defaults to [`source::DEFAULT_SOURCES`].
+    pub fn sources(mut self, sources: Vec<SourceKind>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Extract the creation date and other metadata of the file at `path`.
+    ///
+    /// The container format is determined from the contents of the file, or its
+    /// extension if the contents are not recognised. See [`Container::detect`].
+    pub fn extract_path(&self, path: &Path) -> Result<Metadata, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let detection = Container::detect(&mut reader, path)?;
+        self.extract_file(reader, detection.container, path)
+    }
+
+    /// Extract the creation date and other metadata from `reader`, which holds the
+    /// file at `path` of type `container`.
+    ///
+    /// The path is used for the filename, sidecar and filesystem sources.
+    pub fn extract_file<R: Read + Seek>(
+        &self,
+        reader: R,
+        container: Container,
+        path: &Path,
+    ) -> Result<Metadata, Error> {
+        let contents = self.read_file(reader, container, path)?;
+        Ok(Metadata {
+            creation_date: self.choose(&contents.dates)?,
+            device: contents.device,
+            duration: contents.duration,
+        })
+    }
+
+    /// Read all the creation dates of the file at `path` in `reader`, from the
+    /// enabled sources, without choosing one. See [`CreationDateExtractor::choose`].
+    pub fn read_file<R: Read + Seek>(
+        &self,
+        reader: R,
+        container: Container,
+        path: &Path,
+    ) -> Result<Contents, Error> {
+        let mut contents = self.read_contents(reader, container)?;
+        contents.dates.extend(self.path_dates(path));
+        Ok(contents)
+    }
+
+    /// Extract the creation date and other metadata from `reader`, which holds a
+    /// file of type `container`.
+    ///
+    /// Only the sources in the contents of the file are used.
+    pub fn extract_reader<R: Read + Seek>(
+        &self,
+        reader: R,
+        container: Container,
+    ) -> Result<Metadata, Error> {
+        let contents = self.read_contents(reader, container)?;
+        Ok(Metadata {
+            creation_date: self.choose(&contents.dates)?,
+            device: contents.device,
+            duration: contents.duration,
+        })
+    }
+
+    fn read_contents<R: Read + Seek>(
+        &self,
+        mut reader: R,
+        container: Container,
+    ) -> Result<Contents, Error> {
+        match container {
+            Container::Matroska => {
+                let mkv = Matroska::open(reader)?;
+                let app = |app: &str| Some(String::from(app)).filter(|app| !app.is_empty());
+                Ok(Contents {
+                    dates: mkv_creation_dates(&mkv),
+                    device: Device {
+                        make: matroska_tag(&mkv, "com.apple.quicktime.make").map(String::from),
+                        model: matroska_tag(&mkv, "com.apple.quicktime.model").map(String::from),
+                        writing_app: app(&mkv.info.writing_app),
+                        muxing_app: app(&mkv.info.muxing_app),
+                        ..Device::default()
+                    },
+                    duration: mkv
+                        .info
+                        .duration
+                        .and_then(|duration| Duration::try_from(duration).ok()),
+                })
+            }
+            Container::Mp4 => self.read_mp4(&mut reader),
+            Container::Jpeg => {
+                let mut contents = exif_contents(exif::read_jpeg(&mut reader)?);
+                contents
+                    .dates
+                    .extend(xmp_creation_date(xmp::read_jpeg(&mut reader)?));
+                Ok(contents)
+            }
+            Container::Heif => Ok(exif_contents(exif::read_heif(&mut reader)?)),
+            Container::Tiff => Ok(exif_contents(exif::read_tiff(&mut reader, 0)?)),
+        }
+    }
+
+    /// Read the dates and device of an MPEG4 file.
+    fn read_mp4<R: Read + Seek>(&self, reader: &mut R) -> Result<Contents, Error> {
+        let items = quicktime::read_metadata(reader).map_err(Error::mp4)?;
+        let item = |name: &str| {
+            items
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        // The QuickTime creation date is preferred over the movie header, as
+        // it carries the timezone of the recording and isn't changed by edits
+        let mut dates = item("com.apple.quicktime.creationdate")
+            .and_then(parse_quicktime_date)
+            .map(|datetime| CreationDate {
+                datetime,
+                source: DateSource::QuickTimeCreationDate,
+                clock: Clock::Absolute,
+            })
+            .into_iter()
+            .collect::<Vec<_>>();
+        let moov = quicktime::read_moov(reader)?;
+        dates.extend(self.mp4_creation_dates(mp4_header_times(&moov)));
+        dates.extend(xmp_creation_date(
+            xmp::read_bmff(reader).map_err(Error::mp4)?,
+        ));
+        Ok(Contents {
+            dates,
+            device: Device {
+                make: item("com.apple.quicktime.make").map(String::from),
+                model: item("com.apple.quicktime.model").map(String::from),
+                brand: quicktime::read_brand(reader)?,
+                encoder: quicktime::read_encoder(reader).map_err(Error::mp4)?,
+                ..Device::default()
+            },
+            duration: mp4_duration(&moov),
+        })
+    }
+
+    /// The dates from the sources that don't depend on the contents of the file.
+    fn path_dates(&self, path: &Path) -> Vec<CreationDate> {
+        let mut dates = Vec::new();
+        let mut push = |datetime: Option<(OffsetDateTime, Clock)>, source: DateSource| {
+            if let Some((datetime, clock)) = datetime {
+                dates.push(CreationDate {
+                    datetime,
+                    source,
+                    clock,
+                });
+            }
+        };
+        // These are only read if enabled, as they involve further file system access
+        if self.sources.contains(&SourceKind::Filename) {
+            let name = path.file_name().and_then(|name| name.to_str());
+            let date = name.and_then(filename::parse_date);
+            push(
+                date.map(|date| (date.datetime, Clock::Local)),
+                DateSource::Filename,
+            );
+        }
+        if self.sources.contains(&SourceKind::Sidecar) {
+            push(xmp::sidecar_creation_date(path), DateSource::XmpSidecar);
+        }
+        if self.sources.contains(&SourceKind::Filesystem) {
+            dates.extend(filesystem_dates(path));
+        }
+        dates
+    }
+
+    /// Whether `date` could be a real creation date: not before the earliest date,
+    /// and not in the future (allowing a day for clocks that are a little off).
+    fn plausible(&self, date: &CreationDate) -> bool {
+        let latest = OffsetDateTime::now_utc() + Duration::days(1);
+        (self.earliest..=latest).contains(&date.datetime)
+    }
+
+    /// The possible creation dates of an MPEG4 file from the creation times in its
+    /// movie, track and media headers.
+    ///
+    /// Zero (unset) times are skipped. Each time is read relative to the MP4 epoch,
+    /// as the standard says, and only if that isn't plausible relative to the Unix
+    /// epoch, as some encoders write it.
+    fn mp4_creation_dates(&self, times: Vec<(u64, DateSource)>) -> Vec<CreationDate> {
+        let mut dates = Vec::new();
+        for (creation_time, source) in times {
+            let timestamp = match i64::try_from(creation_time) {
+                Ok(0) | Err(_) => continue,
+                Ok(timestamp) => timestamp,
+            };
+            // Cameras often set the header times from a clock in local time, even
+            // though the standard says they are UTC
+            let date = |timestamp| {
+                OffsetDateTime::from_unix_timestamp(timestamp)
+                    .ok()
+                    .map(|datetime| CreationDate {
+                        datetime,
+                        source,
+                        clock: Clock::Local,
+                    })
+            };
+            match date(timestamp - MP4_EPOCH_OFFSET) {
+                Some(date) if self.plausible(&date) => dates.push(date),
+                mp4_epoch => dates.extend(mp4_epoch.into_iter().chain(date(timestamp))),
+            }
+        }
+        dates
+    }
+
+    /// Choose the first plausible date from the highest priority kind of source.
+    /// Within a kind, dates are tried in the order given.
+    pub fn choose(&self, dates: &[CreationDate]) -> Result<CreationDate, Error> {
+        self.sources
+            .iter()
+            .find_map(|&kind| {
+                dates
+                    .iter()
+                    .filter(|date| date.source.kind() == kind)
+                    .find(|date| self.plausible(date))
+            })
+            .copied()
+            .ok_or(Error::NoCreationDate)
+    }
+}
+
+/// The birth time and modification time of the file at `path`, earliest first.
+///
+/// On Linux the birth time comes from `statx`, and is only available on file
+/// systems that record it. A file can't be modified before it was created, so a
+/// modification time earlier than the birth time means the file was copied with
+/// its modification time preserved, and the modification time is tried first.
+fn filesystem_dates(path: &Path) -> Vec<CreationDate> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Vec::new(),
+    };
+    let times = [
+        (metadata.created(), DateSource::FileBirthTime),
+        (metadata.modified(), DateSource::FileModified),
+    ];
+    let mut dates = times
+        .into_iter()
+        .filter_map(|(time, source)| {
+            time.ok().map(|time| CreationDate {
+                datetime: OffsetDateTime::from(time),
+                source,
+                clock: Clock::Absolute,
+            })
+        })
+        .collect::<Vec<_>>();
+    dates.sort_by_key(|date| date.datetime);
+    dates
+}
+
+fn exif_contents(exif: Option<exif::Exif>) -> Contents {
+    let exif = exif.unwrap_or_default();
+    let dates = exif
+        .date_time_original
+        .map(|(datetime, clock)| CreationDate {
+            datetime,
+            source: DateSource::ExifDateTimeOriginal,
+            clock,
+        });
+    Contents {
+        dates: dates.into_iter().collect(),
+        device: Device {
+            make: exif.make,
+            model: exif.model,
+            ..Device::default()
+        },
+        duration: None,
+    }
+}
+
+/// The creation times in the movie header, then the track header of each track,
+/// then the media header of each track, as stored: seconds since 1904-01-01.
+fn mp4_header_times(moov: &MoovBox) -> Vec<(u64, DateSource)> {
+    let movie = (moov.mvhd.creation_time, DateSource::Mp4MovieHeader);
+    let tracks = moov
+        .traks
+        .iter()
+        .map(|trak| (trak.tkhd.creation_time, DateSource::Mp4TrackHeader));
+    let media = moov
+        .traks
+        .iter()
+        .map(|trak| (trak.mdia.mdhd.creation_time, DateSource::Mp4MediaHeader));
+    std::iter::once(movie).chain(tracks).chain(media).collect()
+}
+
+/// The duration of the movie in the movie header, if it is known.
+///
+/// All ones means the duration isn't known.
+fn mp4_duration(moov: &MoovBox) -> Option<Duration> {
+    let timescale = u64::from(moov.mvhd.timescale);
+    let unknown = match moov.mvhd.version {
+        1 => u64::MAX,
+        _ => u64::from(u32::MAX),
+    };
+    let duration = moov.mvhd.duration;
+    if timescale == 0 || duration == unknown {
+        return None;
+    }
+    let seconds = i64::try_from(duration / timescale).ok()?;
+    let nanos = (duration % timescale) * 1_000_000_000 / timescale;
+    Some(Duration::new(seconds, nanos as i32))
+}
+
+fn xmp_creation_date(xmp: Option<String>) -> Option<CreationDate> {
+    xmp.as_deref()
+        .and_then(xmp::creation_date)
+        .map(|(datetime, clock)| CreationDate {
+            datetime,
+            source: DateSource::Xmp,
+            clock,
+        })
+}
+
+/// The possible creation dates of a Matroska file, most trustworthy first.
+///
+/// The QuickTime creation date tag is preferred over the segment `DateUTC`, as
+/// it carries the timezone of the recording.
+pub fn mkv_creation_dates(mkv: &Matroska) -> Vec<CreationDate> {
+    let quicktime = quicktime_creation_date(mkv).map(|datetime| CreationDate {
+        datetime,
+        source: DateSource::QuickTimeCreationDate,
+        clock: Clock::Absolute,
+    });
+    let date_utc = mkv.info.date_utc.map(|datetime| CreationDate {
+        datetime,
+        source: DateSource::MatroskaDateUtc,
+        clock: Clock::Absolute,
+    });
+    quicktime.into_iter().chain(date_utc).collect()
+}
+
+fn quicktime_creation_date(mkv: &Matroska) -> Option<OffsetDateTime> {
+    matroska_tag(mkv, "com.apple.quicktime.creationdate").and_then(parse_quicktime_date)
+}
+
+/// Parse the ISO 8601 value of a `com.apple.quicktime.creationdate` tag, e.g.
+/// `2023-04-12T14:39:01+1000`.
+fn parse_quicktime_date(s: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(s.trim(), &Iso8601::DEFAULT).ok()
+}
+
+/// Find the value of the first string tag called `name`.
+fn matroska_tag<'a>(mkv: &'a Matroska, name: &str) -> Option<&'a str> {
+    mkv.tags.iter().find_map(|tag| {
+        tag.simple
+            .iter()
+            .find(|simple| simple.name.eq_ignore_ascii_case(name))
+            .and_then(|tag| {
+                tag.value.as_ref().and_then(|val| match val {
+                    TagValue::String(ref s) => Some(s.as_str()),
+                    TagValue::Binary(_) => None,
+                })
+            })
+    })
+}
+
+/// Generate the new path for `path` by prepending the timestamp of `creation_date`
+/// to the file name.
+pub fn generate_new_path(path: &Path, creation_date: OffsetDateTime) -> PathBuf {
+    // prepend a timestamp to the file
+    let mut file_name = OsString::from(creation_date.unix_timestamp().to_string());
+    file_name.push(" ");
+    file_name.push(path.file_name().unwrap()); // file_name should exist at this point
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_new_path() {
+        let path = Path::new("folder/IMG_4792.mkv");
+        let datetime = OffsetDateTime::from_unix_timestamp(1681265941).unwrap();
+        let new_path = generate_new_path(path, datetime);
+        let expected_path = Path::new("folder/1681265941 IMG_4792.mkv");
+        assert_eq!(new_path, expected_path);
+    }
+
+    #[test]
+    fn test_choose() {
+        let extractor = CreationDateExtractor::new();
+        let date = |timestamp, source| CreationDate {
+            datetime: OffsetDateTime::from_unix_timestamp(timestamp).unwrap(),
+            source,
+            clock: Clock::Local,
+        };
+        let times = [0, 1681310341, 1681310341 + MP4_EPOCH_OFFSET as u64];
+        let times = vec![
+            (times[0], DateSource::Mp4MovieHeader),
+            (times[1], DateSource::Mp4MovieHeader),
+            (times[2], DateSource::Mp4TrackHeader),
+        ];
+        let dates = extractor.mp4_creation_dates(times.clone());
+        // Zero is skipped, and the Unix epoch time is read both ways as it isn't
+        // plausible relative to the MP4 epoch
+        assert_eq!(dates.len(), 3);
+        assert_eq!(
+            extractor.choose(&dates).unwrap(),
+            date(1681310341, DateSource::Mp4MovieHeader)
+        );
+
+        let extractor = extractor.sources(vec![SourceKind::Track, SourceKind::Container]);
+        assert_eq!(
+            extractor.choose(&dates).unwrap(),
+            date(1681310341, DateSource::Mp4TrackHeader)
+        );
+        let extractor = extractor.sources(vec![SourceKind::Exif]);
+        assert!(extractor.choose(&dates).is_err());
+
+        // With an earlier earliest date, the MP4 epoch reading is plausible
+        let extractor = CreationDateExtractor::new().earliest(datetime!(1900-01-01 0:00 UTC));
+        let dates = extractor.mp4_creation_dates(times);
+        assert_eq!(dates.len(), 2);
+        assert_eq!(
+            extractor.choose(&dates).unwrap(),
+            date(1681310341 - MP4_EPOCH_OFFSET, DateSource::Mp4MovieHeader)
+        );
+
+        // Before the earliest date, and in the future
+        let dates = [
+            date(0, DateSource::Mp4MovieHeader),
+            date(1681310341 + MP4_EPOCH_OFFSET, DateSource::Mp4MovieHeader),
+        ];
+        let extractor = CreationDateExtractor::new();
+        assert!(extractor.choose(&dates).is_err());
+        let extractor = extractor.earliest(datetime!(1900-01-01 0:00 UTC));
+        assert_eq!(extractor.choose(&dates).unwrap(), dates[0]);
+    }
+
+    #[test]
+    fn test_mp4_header_times() {
+        let mut moov = MoovBox::default();
+        moov.mvhd.creation_time = 1;
+        for (tkhd, mdhd) in [(2, 3), (4, 5)] {
+            moov.traks.push(Default::default());
+            let trak = moov.traks.last_mut().unwrap();
+            trak.tkhd.creation_time = tkhd;
+            trak.mdia.mdhd.creation_time = mdhd;
+        }
+        assert_eq!(
+            mp4_header_times(&moov),
+            [
+                (1, DateSource::Mp4MovieHeader),
+                (2, DateSource::Mp4TrackHeader),
+                (4, DateSource::Mp4TrackHeader),
+                (3, DateSource::Mp4MediaHeader),
+                (5, DateSource::Mp4MediaHeader),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mp4_duration() {
+        let duration = |version, timescale, duration| {
+            let mut moov = MoovBox::default();
+            moov.mvhd.version = version;
+            moov.mvhd.timescale = timescale;
+            moov.mvhd.duration = duration;
+            mp4_duration(&moov)
+        };
+        assert_eq!(duration(0, 600, 1500), Some(Duration::milliseconds(2500)));
+        assert_eq!(
+            duration(1, 1000, 61_001),
+            Some(Duration::milliseconds(61_001))
+        );
+        assert_eq!(duration(0, 600, u64::from(u32::MAX)), None);
+        assert_eq!(duration(1, 600, u64::MAX), None);
+        assert_eq!(duration(0, 0, 1500), None);
+    }
+
+    #[test]
+    fn test_parse_quicktime_date() {
+        let expected = Some(datetime!(2023-04-12 14:39:01 +10:00));
+        assert_eq!(parse_quicktime_date("2023-04-12T14:39:01+1000"), expected);
+        assert_eq!(parse_quicktime_date("2023-04-12T14:39:01+10:00"), expected);
+        assert_eq!(parse_quicktime_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_sniff() {
+        let mkv = b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81";
+        let mov = b"\0\0\0\x14ftypqt  ";
+        let jpeg = b"\xFF\xD8\xFF\xE1\x00\x10Exif";
+        let heic = b"\0\0\0\x18ftypheic\0\0\0\0";
+        let text = b"hello";
+        let sniff = |data: &[u8]| Container::sniff(&mut io::Cursor::new(data)).unwrap();
+        assert_eq!(sniff(mkv), Some(Container::Matroska));
+        assert_eq!(sniff(mov), Some(Container::Mp4));
+        assert_eq!(sniff(jpeg), Some(Container::Jpeg));
+        assert_eq!(sniff(heic), Some(Container::Heif));
+        assert_eq!(sniff(text), None);
+    }
+}
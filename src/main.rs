@@ -1,177 +1,1261 @@
-use std::ffi::OsString;
-use std::fs;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{self, Path, PathBuf};
 use std::process::ExitCode;
 
-use matroska::{Matroska, TagValue};
-use mp4::Mp4Reader;
-use time::format_description::well_known::{Iso8601, Rfc2822};
-use time::{Duration, OffsetDateTime};
+use mkv_rename::align::{self, MIN_CORRELATION};
+use mkv_rename::audio::{Audio, Window};
+use mkv_rename::config::{Config, Rule};
+use mkv_rename::drift::{DeviceId, DriftCorrection, Reference};
+use mkv_rename::duplicates::{DuplicateAction, Index, IndexEntry, DEFAULT_INDEX_NAME};
+use mkv_rename::journal::{self, JournalEntry};
+use mkv_rename::manifest::{self, ManifestEntry};
+use mkv_rename::patch;
+use mkv_rename::rename::{self, ConflictPolicy, Mode};
+use mkv_rename::report::{OutputFormat, Record, Reporter, Status};
+use mkv_rename::source::SourceKind;
+use mkv_rename::template::{NameMatch, Template, TemplateContext, DEFAULT_DEST_TEMPLATE};
+use mkv_rename::tz::{LocalTime, TimeZone};
+use mkv_rename::walk::WalkOptions;
+use mkv_rename::{
+    filename, Clock, Container, CreationDate, CreationDateExtractor, Error, Metadata,
+    DEFAULT_EARLIEST_DATE,
+};
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use time::format_description::BorrowedFormatItem;
+use time::macros::format_description;
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime};
+
+/// The format of times without an offset in messages.
+const LOCAL_TIME_FORMAT: &[BorrowedFormatItem<'_>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+
+mod flags {
+    use std::path::PathBuf;
+
+    use glob::Pattern;
+    use mkv_rename::drift::Reference;
+    use mkv_rename::duplicates::DuplicateAction;
+    use mkv_rename::rename::{ConflictPolicy, Mode};
+    use mkv_rename::report::OutputFormat;
+    use mkv_rename::source::SourceOrder;
+    use mkv_rename::template::Template;
+
+    xflags::xflags! {
+        cmd mkv-rename {
+            /// Rename files to start with their creation date
+            default cmd rename {
+                /// Don't rename files, just print what would be done
+                optional -n,--dry-run
+                /// Offset in hours (can be fractional) to add to timestamps read from file
+                ///
+                /// Some cameras appear to store the creation date in local time, without a timezone.
+                /// This flag allows those times to be adjusted. Times stored with their offset
+                /// from UTC, or in UTC by definition, are left alone.
+                optional -t,--tz-offset offset: f32
+                /// Time zone the camera clock was set to, e.g. Europe/Berlin
+                ///
+                /// Local times read from files are taken to be in this zone, with daylight saving
+                /// resolved for each file, and other times are shown in this zone. Local times
+                /// that happened twice as the clocks went back are taken to be the earlier one,
+                /// and times skipped as the clocks went forward are moved forward by the change.
+                /// Zones are read from the directory in TZDIR, or /usr/share/zoneinfo. Can't be
+                /// used with --tz-offset.
+                optional --tz zone: String
+                /// Ignore creation dates before the start of this year (default 1990)
+                ///
+                /// Some cameras write zero or bogus dates. These are ignored in favour of
+                /// other dates in the file, or the file is skipped if there are none.
+                optional --earliest-year year: i32
+                /// Comma separated list of where to look for creation dates, in priority order
+                ///
+                /// Sources: container (QuickTime tags, movie header, Matroska segment info),
+                /// track (track and media headers), exif (embedded EXIF and XMP), filename,
+                /// sidecar (XMP sidecar files), and filesystem (birth or modification time, a
+                /// low confidence guess). Sources that aren't listed are not used. Defaults to
+                /// container,track,exif,filename,sidecar.
+                optional --date-source sources: SourceOrder
+                /// Template for the new file name
+                ///
+                /// Date components are written as in the time crate, e.g. [year]-[month]-[day].
+                /// Placeholders: {timestamp}, {name}, {stem}, {ext}, {counter} (or {counter:N} to
+                /// zero-pad to N digits), {make}, {model} and {source}. Defaults to {timestamp} {name}.
+                /// It can't contain '/', use --dest-format for directories.
+                optional -f,--format template: Template
+                /// Process the files in directories and their subdirectories
+                ///
+                /// Files of an unsupported type found this way are skipped.
+                optional -r,--recursive
+                /// Only process files matching this glob pattern when recursing (can be repeated)
+                ///
+                /// Patterns containing a / are matched against the path relative to the
+                /// directory, others against the file name. Matching is case-insensitive.
+                repeated --include pattern: Pattern
+                /// Skip files and directories matching this glob pattern when recursing (can be repeated)
+                repeated --exclude pattern: Pattern
+                /// Follow symlinks to directories when recursing
+                optional -L,--follow-symlinks
+                /// Put files in directories under this one, named by --dest-format
+                ///
+                /// Missing directories are created. Files already named for their creation date
+                /// are moved there too.
+                optional --dest root: PathBuf
+                /// Template for the directories under --dest, e.g. [year]/[month]
+                ///
+                /// Takes the same date components and placeholders as --format. Defaults to
+                /// [year]/[year]-[month]-[day].
+                optional --dest-format template: String
+                /// How to put files at their new path: rename (the default), move, copy,
+                /// hardlink, or reflink
+                ///
+                /// rename fails if the new path is on another file system, while move copies the
+                /// file there, syncs and checks the copy, then deletes the original. copy keeps
+                /// the permissions and modification time, and reflink shares the data with the
+                /// original on file systems that support it, such as btrfs and XFS.
+                optional --mode mode: Mode
+                /// What to do when the new name is already taken: skip, fail (the default),
+                /// or suffix to append -1, -2, etc. to the new name
+                optional --on-conflict policy: ConflictPolicy
+                /// Find files with the same contents as another file processed, or one already
+                /// in --dest, and report, skip, hardlink, or quarantine them
+                ///
+                /// Files are compared by creation date, duration and size, then by SHA-256 hash.
+                /// report warns and processes them as usual, and skip leaves them alone. hardlink
+                /// gives them their new path as a hard link to the file they duplicate, then
+                /// removes them unless --mode keeps the original. quarantine puts them in the
+                /// --quarantine directory under their current name.
+                optional --duplicates action: DuplicateAction
+                /// Directory to put duplicates in with --duplicates quarantine
+                optional --quarantine dir: PathBuf
+                /// Keep the index of files to find duplicates of in this file
+                ///
+                /// By default the index is kept in .mkv-rename-index.jsonl in the --dest
+                /// directory, and built from the files there the first time. Without either,
+                /// files are only checked against the others processed.
+                optional --index path: PathBuf
+                /// Write the creation date, with any offset and correction applied, into the
+                /// metadata of the files too
+                ///
+                /// The creation and modification times in the movie, track and media headers
+                /// of MP4 and MOV files are overwritten in UTC, so run the tool on them again
+                /// without an offset. Files whose date is a local time are reported as errors
+                /// unless --tz, --tz-offset or a rule says where it was. The DateUTC of Matroska files is replaced, or one is added
+                /// if the segment info has a Void element with room for it. Files are changed in
+                /// place, after they are renamed, moved or copied, and the old bytes are put
+                /// back if a file doesn't read back with the new date. Hard links share their
+                /// data with the original, so this can't be used with them.
+                optional --write-metadata
+                /// Set the modification time of the files to their creation date, with any
+                /// offset and correction applied
+                ///
+                /// File browsers and ls -t sort by modification time, which for files copied
+                /// off a card is when they were copied. This is done after files are renamed,
+                /// moved or copied.
+                optional --touch
+                /// Set the access time too, with --touch
+                optional --touch-atime
+                /// Don't rename files, only --touch them or --write-metadata into them
+                ///
+                /// Files are still read and checked for duplicates, and their dates printed.
+                optional --no-rename
+                /// Rename files even if their name already starts with their creation date
+                ///
+                /// Without this, files already named for their creation date are skipped, and
+                /// files named for a different date (e.g. after changing --tz-offset) have
+                /// that date replaced.
+                optional --force
+                /// Record renames in this journal
+                ///
+                /// By default renames are recorded in .mkv-rename-journal.jsonl in the
+                /// directory of each renamed file.
+                optional -j,--journal path: PathBuf
+                /// Record the SHA-256 hashes of copies in this manifest
+                ///
+                /// Copies are hashed as they are written, then read back and hashed again to
+                /// check them. By default the hashes are recorded in .mkv-rename-manifest.jsonl
+                /// in the directory of each copy.
+                optional --manifest path: PathBuf
+                /// Apply the per-camera rules in this TOML file
+                ///
+                /// Rules match files on their camera make and model, MPEG4 brand or encoder,
+                /// Matroska writing or muxing app, or path, and set tz-offset or tz, format and
+                /// date-source for them. See the README for the format.
+                optional --config path: PathBuf
+                /// A file recorded at a known time, as PATH=TIME, to correct the camera clock
+                /// (can be repeated)
+                ///
+                /// TIME is in RFC 3339 format, e.g. 2023-07-01T12:00:05+02:00. The difference from
+                /// the creation date of the file is added to every file from the same camera, as
+                /// identified by its make, model and encoder. With references at different times
+                /// the difference is interpolated between them, to correct clocks that drift.
+                repeated --reference reference: Reference
+                /// How to report what happened to each file: text (the default), json for a JSON
+                /// object per line, or csv
+                ///
+                /// Records hold the path, new path, creation date in RFC 3339 format, its source,
+                /// the offset applied in seconds, the container, the status (renamed,
+                /// would-rename, updated, would-update, skipped or failed), the kind of error
+                /// and message for failures, and the file duplicated with --duplicates. Errors
+                /// and warnings are still written to stderr.
+                optional --output format: OutputFormat
+                /// Files to process, or directories with --recursive
+                repeated paths: PathBuf
+            }
+
+            /// Reverse the renames recorded in a journal
+            ///
+            /// Files that have been renamed or moved since are left alone.
+            cmd undo {
+                /// Don't rename files, just print what would be done
+                optional -n,--dry-run
+                /// Journals to undo, or directories containing a .mkv-rename-journal.jsonl
+                repeated journals: PathBuf
+            }
+
+            /// Find the errors of camera clocks by matching the audio of clips recorded at the
+            /// same time
+            ///
+            /// Each clip is lined up with the first by cross-correlating their audio, which must
+            /// be uncompressed (PCM) or AAC-LC. The difference from their creation dates is the
+            /// error of the clock of the camera that recorded the clip, which is printed as a
+            /// --reference for rename to correct. The first clip should come from a camera with
+            /// a trusted clock, such as a phone.
+            ///
+            /// Up to 5 minutes of each clip is decoded, from where it overlaps the reference by
+            /// their creation dates, with the reference searched 5 minutes either side for
+            /// clocks that are out. Clips that don't match there are matched by the first 5
+            /// minutes of both.
+            cmd align {
+                /// The clip to line the others up with
+                required reference: PathBuf
+                /// Clips that overlap it
+                repeated clips: PathBuf
+            }
+        }
+    }
+}
 
 struct Flags {
     dry_run: bool,
-    /// Offset in seconds
-    offset: Duration,
+    /// Offset in seconds, if one was given
+    offset: Option<Duration>,
+    /// Time zone that times read from files are local to
+    zone: Option<TimeZone>,
+    earliest: OffsetDateTime,
+    sources: Vec<SourceKind>,
+    template: Template,
+    walk_options: WalkOptions,
+    on_conflict: ConflictPolicy,
+    force: bool,
+    write_metadata: bool,
+    touch: bool,
+    /// Whether to set the access time with --touch
+    touch_atime: bool,
+    no_rename: bool,
+    journal: Option<PathBuf>,
+    manifest: Option<PathBuf>,
+    /// The root of the directories to put files in
+    dest: Option<PathBuf>,
+    dest_template: Template,
+    mode: Mode,
+    /// What to do with duplicates, if they're looked for
+    duplicates: Option<DuplicateAction>,
+    quarantine: Option<PathBuf>,
+    index: Option<PathBuf>,
+    config: Option<Config>,
+    /// Corrections for camera clocks, measured from the references
+    drift: DriftCorrection,
+    output: OutputFormat,
 }
 
-fn main() -> ExitCode {
-    let all_flags = xflags::parse_or_exit! {
-        /// Don't rename files, just print what would be done
-        optional -n,--dry-run
-        /// Offset in hours (can be fractional) to add to timestamps read from file
-        ///
-        /// Some cameras appear to store the creation date in local time, without a timezone.
-        /// This flag allows those times to be adjusted.
-        optional -t,--tz-offset offset: f32
-        /// Files to process
-        repeated paths: PathBuf
-    };
-    // TODO: use xflags::xflags! macro and make this a TryFrom impl
-    // TODO: use let-else when CI/Alpine is on a new enough Rust version
-    // let Some(offset) = f32_to_i32((all_flags.tz_offset.unwrap_or_default() * 60. * 60.).round()) else {
-    //     eprintln!("offset too big");
-    //     return ExitCode::FAILURE;
-    // };
-    let offset = match f32_to_i32((all_flags.tz_offset.unwrap_or_default() * 60. * 60.).round()) {
-        Some(offset) => offset,
-        None => {
-            eprintln!("offset too big");
-            return ExitCode::FAILURE;
+/// The settings for one file: those on the command line, overridden by the rule
+/// that the file matches.
+struct Settings<'a> {
+    offset: Option<Duration>,
+    zone: Option<&'a TimeZone>,
+    template: &'a Template,
+    sources: &'a [SourceKind],
+    /// The name of the rule
+    rule: Option<&'a str>,
+}
+
+impl TryFrom<&flags::Rename> for Flags {
+    type Error = Error;
+
+    fn try_from(flags: &flags::Rename) -> Result<Self, Self::Error> {
+        let offset = f32_to_i32((flags.tz_offset.unwrap_or_default() * 60. * 60.).round())
+            .ok_or(Error::OffsetOutOfRange)?;
+        let zone = match &flags.tz {
+            Some(_) if flags.tz_offset.is_some() => {
+                return Err(Error::InvalidOption(String::from(
+                    "--tz and --tz-offset can't be used together",
+                )))
+            }
+            Some(name) => Some(TimeZone::load(name).map_err(|err| {
+                Error::InvalidOption(format!("unable to load time zone {}: {}", name, err))
+            })?),
+            None => None,
+        };
+        if flags.dest_format.is_some() && flags.dest.is_none() {
+            return Err(Error::InvalidOption(String::from(
+                "--dest-format can only be used with --dest",
+            )));
         }
+        match (flags.duplicates, &flags.quarantine) {
+            (Some(DuplicateAction::Quarantine), None) => {
+                return Err(Error::InvalidOption(String::from(
+                    "--duplicates quarantine needs a --quarantine directory",
+                )))
+            }
+            (Some(DuplicateAction::Quarantine), Some(_)) => (),
+            (_, Some(_)) => {
+                return Err(Error::InvalidOption(String::from(
+                    "--quarantine can only be used with --duplicates quarantine",
+                )))
+            }
+            (_, None) => (),
+        }
+        if flags.touch_atime && !flags.touch {
+            return Err(Error::InvalidOption(String::from(
+                "--touch-atime can only be used with --touch",
+            )));
+        }
+        if flags.no_rename {
+            let renaming = if flags.dest.is_some() {
+                Some("--dest")
+            } else if flags.mode.is_some() {
+                Some("--mode")
+            } else if matches!(
+                flags.duplicates,
+                Some(DuplicateAction::HardLink | DuplicateAction::Quarantine)
+            ) {
+                Some("--duplicates hardlink or quarantine")
+            } else {
+                None
+            };
+            if let Some(option) = renaming {
+                return Err(Error::InvalidOption(format!(
+                    "{} can't be used with --no-rename",
+                    option
+                )));
+            }
+        }
+        if flags.write_metadata {
+            // Hard links share their data with the original, which would change too
+            let linking = if flags.mode == Some(Mode::HardLink) {
+                Some("--mode hardlink")
+            } else if flags.duplicates == Some(DuplicateAction::HardLink) {
+                Some("--duplicates hardlink")
+            } else {
+                None
+            };
+            if let Some(option) = linking {
+                return Err(Error::InvalidOption(format!(
+                    "{} can't be used with --write-metadata",
+                    option
+                )));
+            }
+        }
+        if flags.index.is_some() && flags.duplicates.is_none() {
+            return Err(Error::InvalidOption(String::from(
+                "--index can only be used with --duplicates",
+            )));
+        }
+        let earliest = match flags.earliest_year {
+            Some(year) => Date::from_calendar_date(year, Month::January, 1)
+                .map_err(|_| Error::InvalidOption(format!("year {} out of range", year)))?
+                .midnight()
+                .assume_utc(),
+            None => DEFAULT_EARLIEST_DATE,
+        };
+        Ok(Flags {
+            dry_run: flags.dry_run,
+            offset: flags.tz_offset.map(|_| Duration::new(i64::from(offset), 0)),
+            zone,
+            earliest,
+            sources: flags.date_source.clone().unwrap_or_default().0,
+            template: flags.format.clone().unwrap_or_default(),
+            walk_options: WalkOptions {
+                include: flags.include.clone(),
+                exclude: flags.exclude.clone(),
+                follow_symlinks: flags.follow_symlinks,
+            },
+            on_conflict: flags.on_conflict.unwrap_or_default(),
+            force: flags.force,
+            write_metadata: flags.write_metadata,
+            touch: flags.touch,
+            touch_atime: flags.touch_atime,
+            no_rename: flags.no_rename,
+            journal: flags.journal.clone(),
+            manifest: flags.manifest.clone(),
+            dest: flags.dest.clone(),
+            dest_template: Template::parse_dirs(
+                flags
+                    .dest_format
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DEST_TEMPLATE),
+            )?,
+            mode: flags.mode.unwrap_or_default(),
+            duplicates: flags.duplicates,
+            quarantine: flags.quarantine.clone(),
+            index: flags.index.clone(),
+            config: flags.config.as_deref().map(Config::load).transpose()?,
+            drift: DriftCorrection::new(),
+            output: flags.output.unwrap_or_default(),
+        })
+    }
+}
+
+impl Flags {
+    /// The settings for a file that matches `rule`.
+    fn settings<'a>(&'a self, rule: Option<&'a Rule>) -> Settings<'a> {
+        let mut settings = Settings {
+            offset: self.offset,
+            zone: self.zone.as_ref(),
+            template: &self.template,
+            sources: &self.sources,
+            rule: None,
+        };
+        if let Some(rule) = rule {
+            let rule_settings = &rule.settings;
+            // An offset or zone in the rule replaces both on the command line
+            if rule_settings.offset.is_some() || rule_settings.zone.is_some() {
+                settings.offset = rule_settings.offset;
+                settings.zone = rule_settings.zone.as_ref();
+            }
+            if let Some(template) = &rule_settings.template {
+                settings.template = template;
+            }
+            if let Some(sources) = &rule_settings.sources {
+                settings.sources = sources;
+            }
+            settings.rule = Some(&rule.name);
+        }
+        settings
+    }
+}
+
+fn main() -> ExitCode {
+    let result = match flags::MkvRename::from_env_or_exit().subcommand {
+        flags::MkvRenameCmd::Rename(cmd) => rename(cmd),
+        flags::MkvRenameCmd::Undo(cmd) => undo(cmd),
+        flags::MkvRenameCmd::Align(cmd) => align(cmd),
     };
-    let flags = Flags {
-        dry_run: all_flags.dry_run,
-        offset: Duration::new(i64::from(offset), 0),
-    };
+    match result {
+        Ok(None) => ExitCode::SUCCESS,
+        Ok(Some(exit_code)) => ExitCode::from(exit_code),
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
 
-    let mut ok = true;
-    for f in all_flags.paths {
+/// Rename the files in `cmd`, returning the exit code of the first file that failed.
+fn rename(cmd: flags::Rename) -> Result<Option<u8>, Error> {
+    let mut flags = Flags::try_from(&cmd)?;
+    // Read the dates from every source that might be used, and choose between them
+    // once the rule for the file is known
+    let mut sources = flags.sources.clone();
+    for kind in flags.config.iter().flat_map(Config::sources) {
+        if !sources.contains(&kind) {
+            sources.push(kind);
+        }
+    }
+    let extractor = CreationDateExtractor::new()
+        .earliest(flags.earliest)
+        .sources(sources);
+    for reference in &cmd.reference {
+        measure_drift(reference, &extractor, &mut flags)?;
+    }
+    let mut index = open_index(&flags, &extractor)?;
+    let mut reporter = Reporter::new(flags.output, io::stdout());
+    let mut counter = 0;
+    // The exit code of the first failure
+    let mut exit_code = None;
+    for f in cmd.paths {
         let path = Path::new(&f);
-        match process(path, &flags) {
-            Ok(()) => (),
-            Err(err) => {
-                eprintln!("Error processing {}: {}", path.display(), err);
-                ok = false;
+        if cmd.recursive && path.is_dir() {
+            for entry in flags.walk_options.walk(path) {
+                match entry {
+                    Ok(path) => {
+                        match process(&path, &extractor, &flags, &mut counter, index.as_mut()) {
+                            // Unsupported files are expected when walking directories
+                            Err(Error::UnsupportedFormat) => (),
+                            result => report_record(&mut reporter, &path, result, &mut exit_code)?,
+                        }
+                    }
+                    Err(err) => report_record(&mut reporter, path, Err(err), &mut exit_code)?,
+                }
             }
+        } else {
+            let result = process(path, &extractor, &flags, &mut counter, index.as_mut());
+            report_record(&mut reporter, path, result, &mut exit_code)?;
         }
     }
 
-    if ok {
-        ExitCode::SUCCESS
-    } else {
-        ExitCode::FAILURE
-    }
+    Ok(exit_code)
 }
 
-fn process(path: &Path, flags: &Flags) -> Result<(), String> {
-    match path
-        .extension()
-        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
-        .as_deref()
-    {
-        Some("mkv") => process_matroska(path, flags),
-        Some("mov" | "mp4" | "m4v") => process_mp4(path, flags),
-        _ => Err(String::from("unknown file type")),
+/// The index of files to find duplicates of, if they're looked for.
+///
+/// This is the index given by `--index`, or the one kept in the root of `--dest`,
+/// which is built from the files there the first time. Without either, the index
+/// starts empty and isn't saved.
+fn open_index(flags: &Flags, extractor: &CreationDateExtractor) -> Result<Option<Index>, Error> {
+    if flags.duplicates.is_none() {
+        return Ok(None);
     }
+    let index_path = match (&flags.index, &flags.dest) {
+        (Some(index_path), _) => index_path.clone(),
+        (None, Some(root)) => root.join(DEFAULT_INDEX_NAME),
+        (None, None) => return Ok(Some(Index::new())),
+    };
+    let built = index_path.exists();
+    let mut index = Index::load(&index_path, !flags.dry_run)?;
+    match &flags.dest {
+        Some(root) if !built && root.is_dir() => {
+            eprintln!("Indexing the files in {}", root.display());
+            for path in WalkOptions::default().walk(root).flatten() {
+                // Files without a date can't be matched anyway
+                if let Ok(dated) = read_date(&path, extractor, flags) {
+                    index.insert(IndexEntry::new(path::absolute(&path)?, &dated.metadata)?)?;
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(Some(index))
 }
 
-fn process_matroska(path: &Path, flags: &Flags) -> Result<(), String> {
-    let mkv = matroska::open(path).map_err(|err| err.to_string())?;
-    let datetime = mkv_creation_date(&mkv)
-        .ok_or_else(|| String::from("unable to determine creation date"))?
-        + flags.offset;
+/// Undo the renames in the journals in `cmd`, returning the exit code of the first
+/// rename that could not be undone.
+fn undo(cmd: flags::Undo) -> Result<Option<u8>, Error> {
+    let mut exit_code = None;
+    for path in cmd.journals {
+        let journal_path = if path.is_dir() {
+            journal::default_journal_path(&path)
+        } else {
+            path
+        };
 
-    let new_path = generate_new_path(path, datetime);
-    println!(
-        "{} -> {} ({})",
-        path.display(),
-        new_path.display(),
-        datetime.format(&Rfc2822).unwrap()
-    );
-    maybe_do_rename(path, &new_path, flags.dry_run)?;
+        let entries = journal::read(&journal_path)?;
+        let mut remaining = Vec::new();
+        // Undo in reverse order in case a file was renamed more than once
+        for entry in entries.into_iter().rev() {
+            match journal::undo(&entry, cmd.dry_run) {
+                Ok(()) => println!(
+                    "{} -> {}",
+                    entry.new_path.display(),
+                    entry.old_path.display()
+                ),
+                Err(err) => {
+                    eprintln!("Error undoing {}: {}", entry.new_path.display(), err);
+                    exit_code.get_or_insert(err.exit_code());
+                    remaining.push(entry);
+                }
+            }
+        }
 
-    Ok(())
+        if !cmd.dry_run {
+            remaining.reverse();
+            journal::write(&journal_path, &remaining)?;
+        }
+    }
+
+    Ok(exit_code)
 }
 
-fn process_mp4(path: &Path, flags: &Flags) -> Result<(), String> {
-    let f = File::open(path).map_err(|err| err.to_string())?;
-    let size = f.metadata().map_err(|err| err.to_string())?.len();
-    let reader = BufReader::new(f);
-    let mp4 = Mp4Reader::read_header(reader, size).map_err(|err| err.to_string())?;
-    let datetime = mp4_creation_date(&mp4)
-        .ok_or_else(|| String::from("unable to determine creation date"))?
-        + flags.offset;
+/// Line the clips in `cmd` up with the reference clip, returning the exit code of
+/// the first clip that couldn't be.
+fn align(cmd: flags::Align) -> Result<Option<u8>, Error> {
+    let extractor = CreationDateExtractor::new();
+    let reference = extractor.extract_path(&cmd.reference)?.creation_date;
+    if reference.clock == Clock::Local {
+        eprintln!(
+            "Warning: the creation date of {} is a local time, so the times below are in the \
+             time zone of its camera, shown as UTC",
+            cmd.reference.display()
+        );
+    }
+
+    let mut exit_code = None;
+    for path in &cmd.clips {
+        let result = align_clip(path, &cmd.reference, &reference, &extractor);
+        report(path, result, &mut exit_code);
+    }
+    Ok(exit_code)
+}
 
-    let new_path = generate_new_path(path, datetime);
+/// Line up the clip at `path` with the reference clip, and print the error of its
+/// camera's clock.
+fn align_clip(
+    path: &Path,
+    reference_path: &Path,
+    reference: &CreationDate,
+    extractor: &CreationDateExtractor,
+) -> Result<(), Error> {
+    let metadata = extractor.extract_path(path)?;
+    let dated_lag = metadata.creation_date.datetime - reference.datetime;
+    let alignment = match_audio(reference_path, path, dated_lag)?;
+
+    // Matching is more precise than this, but clocks aren't
+    let lag = Duration::milliseconds((alignment.lag.as_seconds_f64() * 1000.).round() as i64);
+    // When the clip really started, by the clock of the reference
+    let start = reference.datetime + lag;
+    let error = dated_lag - lag;
+    let seconds = |duration: Duration| duration.whole_milliseconds() as f64 / 1000.;
     println!(
-        "{} -> {} ({})",
+        "{} starts {}s after {} by its audio (correlation {:.2}), and {}s after by its \
+         creation date, so its clock is {}s {}",
         path.display(),
-        new_path.display(),
-        datetime.format(&Rfc2822).unwrap()
+        seconds(lag),
+        reference_path.display(),
+        alignment.correlation,
+        seconds(dated_lag),
+        seconds(error.abs()),
+        if error.is_negative() { "slow" } else { "fast" }
     );
-    maybe_do_rename(path, &new_path, flags.dry_run)?;
-
+    println!(
+        "  --reference '{}={}'",
+        path.display(),
+        start.format(&Rfc3339).unwrap()
+    );
+    if DeviceId::new(&metadata.device).is_none() {
+        eprintln!(
+            "Warning: {} has no make, model or encoder to identify its camera, so the \
+             reference can't be used",
+            path.display()
+        );
+    }
     Ok(())
 }
 
-fn maybe_do_rename(path: &Path, new_path: &PathBuf, dry_run: bool) -> Result<(), String> {
-    if !dry_run {
-        fs::rename(path, &new_path)
-            .map_err(|err| format!("unable to rename to {}: {}", new_path.display(), err))?;
+/// Line up the audio of the clip at `path` with the reference clip, first where
+/// they overlap by their creation dates, then at the start of both.
+fn match_audio(
+    reference_path: &Path,
+    path: &Path,
+    dated_lag: Duration,
+) -> Result<align::Alignment, Error> {
+    let matches = |reference: Window, clip: Window| -> Result<_, Error> {
+        let reference = Audio::read_path(reference_path, reference)?;
+        let clip = Audio::read_path(path, clip)?;
+        Ok(align::align(&reference, &clip)
+            .filter(|alignment| alignment.correlation >= MIN_CORRELATION))
+    };
+
+    let (reference, clip) = align::overlap_windows(dated_lag);
+    if reference.start == Duration::ZERO && clip.start == Duration::ZERO {
+        // The overlap covers the start of both
+        return matches(reference, clip)?.ok_or(Error::AudioMismatch);
+    }
+    // The dates can be out by more than the clocks, such as by a time zone
+    if let Ok(Some(alignment)) = matches(reference, clip) {
+        return Ok(alignment);
     }
+    matches(Window::START, Window::START)?.ok_or(Error::AudioMismatch)
+}
+
+fn report(path: &Path, result: Result<(), Error>, exit_code: &mut Option<u8>) {
+    if let Err(err) = result {
+        eprintln!("Error processing {}: {}", path.display(), err);
+        exit_code.get_or_insert(err.exit_code());
+    }
+}
+
+/// Report the outcome for the file at `path`, in the output format and as an
+/// error message if it failed.
+fn report_record(
+    reporter: &mut Reporter<io::Stdout>,
+    path: &Path,
+    result: Result<Record, Error>,
+    exit_code: &mut Option<u8>,
+) -> Result<(), Error> {
+    let record = match result {
+        Ok(record) => record,
+        Err(err) => {
+            let record = Record::failed(path.to_path_buf(), &err);
+            report(path, Err(err), exit_code);
+            record
+        }
+    };
+    reporter.write(&record)?;
     Ok(())
 }
 
-fn mp4_creation_date<R>(mp4: &Mp4Reader<R>) -> Option<OffsetDateTime> {
-    let creation_time = mp4.moov.mvhd.creation_time;
+/// Measure the error of the clock of the camera that recorded a reference file.
+fn measure_drift(
+    reference: &Reference,
+    extractor: &CreationDateExtractor,
+    flags: &mut Flags,
+) -> Result<(), Error> {
+    let path = &reference.path;
+    let invalid = |msg: String| {
+        Error::InvalidOption(format!("invalid reference {}: {}", path.display(), msg))
+    };
+    let dated = read_date(path, extractor, flags).map_err(|err| invalid(err.to_string()))?;
+    let device = DeviceId::new(&dated.metadata.device).ok_or_else(|| {
+        invalid(String::from(
+            "it has no make, model or encoder to identify its camera",
+        ))
+    })?;
+    let datetime = dated.datetime;
+    flags
+        .drift
+        .add(device, datetime, reference.datetime)
+        .map_err(invalid)
+}
 
-    // convert from MP4 epoch (1904-01-01) to Unix epoch (1970-01-01)
-    let timestamp = creation_time as i64 - 2082844800;
-    OffsetDateTime::from_unix_timestamp(timestamp).ok()
+/// A file's metadata, the settings for it, and its creation date with the offset
+/// or time zone applied.
+struct Dated<'a> {
+    container: Container,
+    metadata: Metadata,
+    settings: Settings<'a>,
+    datetime: OffsetDateTime,
 }
 
-fn mkv_creation_date(mkv: &Matroska) -> Option<OffsetDateTime> {
-    quicktime_creation_date(mkv).or(mkv.info.date_utc)
+fn read_date<'a>(
+    path: &Path,
+    extractor: &CreationDateExtractor,
+    flags: &'a Flags,
+) -> Result<Dated<'a>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let detection = Container::detect(&mut reader, path)?;
+    if let Some(from_extension) = detection.extension_mismatch() {
+        eprintln!(
+            "Warning: {} has the extension of a {} file but contains {}",
+            path.display(),
+            from_extension,
+            detection.container
+        );
+    }
+    let contents = extractor.read_file(reader, detection.container, path)?;
+    let rule = flags
+        .config
+        .as_ref()
+        .and_then(|config| config.find(path, &contents.device));
+    let settings = flags.settings(rule);
+    let metadata = Metadata {
+        creation_date: extractor
+            .clone()
+            .sources(settings.sources.to_vec())
+            .choose(&contents.dates)?,
+        device: contents.device,
+        duration: contents.duration,
+    };
+    let datetime = adjust(path, &metadata.creation_date, &settings);
+    Ok(Dated {
+        container: detection.container,
+        metadata,
+        settings,
+        datetime,
+    })
 }
 
-fn quicktime_creation_date(mkv: &Matroska) -> Option<OffsetDateTime> {
-    mkv.tags.iter().find_map(|tag| {
-        tag.simple
-            .iter()
-            .find(|simple| simple.name.to_ascii_lowercase() == "com.apple.quicktime.creationdate")
-            .and_then(|tag| {
-                tag.value.as_ref().and_then(|val| match val {
-                    TagValue::String(ref s) => OffsetDateTime::parse(s, &Iso8601::DEFAULT).ok(),
-                    TagValue::Binary(_) => None,
-                })
+fn process(
+    path: &Path,
+    extractor: &CreationDateExtractor,
+    flags: &Flags,
+    counter: &mut u64,
+    mut index: Option<&mut Index>,
+) -> Result<Record, Error> {
+    // The date is read from the file as it was before an interrupted write
+    if flags.write_metadata && !flags.dry_run && patch::restore_interrupted(path)? {
+        eprintln!(
+            "Warning: put back the old metadata of {}, as an earlier run was interrupted while writing into it",
+            path.display()
+        );
+    }
+    let Dated {
+        container,
+        metadata,
+        settings,
+        mut datetime,
+    } = read_date(path, extractor, flags)?;
+    check_filename_date(path, &metadata.creation_date);
+    // Without an offset or zone a local time would be written as if it were UTC
+    if flags.write_metadata
+        && metadata.creation_date.clock == Clock::Local
+        && settings.offset.is_none()
+        && settings.zone.is_none()
+    {
+        return Err(Error::WriteMetadata(format!(
+            "the {} date is a local time, and needs --tz or --tz-offset (or a rule with one) to be written",
+            metadata.creation_date.source
+        )));
+    }
+    let correction = DeviceId::new(&metadata.device)
+        .and_then(|device| flags.drift.correction(&device, datetime));
+    if let Some(correction) = correction {
+        datetime += correction;
+    }
+
+    let source = metadata.creation_date.source;
+    let mut record = Record {
+        path: path.to_path_buf(),
+        new_path: None,
+        datetime: Some(datetime),
+        source: Some(source),
+        offset: Some((datetime - metadata.creation_date.datetime).whole_seconds()),
+        container: Some(container),
+        status: Status::Skipped,
+        error: None,
+        message: None,
+        duplicate_of: None,
+    };
+    let text = flags.output == OutputFormat::Text;
+
+    // Look for a file with the same contents before doing anything with this one
+    let mut duplicate = None;
+    let mut index_entry = match index.as_deref_mut() {
+        Some(index) => {
+            let mut entry = IndexEntry::new(path::absolute(path)?, &metadata)?;
+            duplicate = index.find_duplicate(&mut entry)?;
+            Some(entry)
+        }
+        None => None,
+    };
+    record.duplicate_of = duplicate.clone();
+    let action = duplicate.as_ref().and(flags.duplicates);
+    match (&duplicate, action) {
+        (Some(original), Some(DuplicateAction::Skip)) => {
+            return skip_duplicate(path, original, text, record)
+        }
+        (Some(original), Some(DuplicateAction::Report)) => eprintln!(
+            "Warning: {} is a duplicate of {}",
+            path.display(),
+            original.display()
+        ),
+        _ => (),
+    }
+
+    if flags.no_rename {
+        if text {
+            println!(
+                "{}: {} (from {}, {} time)",
+                path.display(),
+                datetime.format(&Rfc2822).unwrap(),
+                source,
+                metadata.creation_date.clock
+            );
+        }
+        record.message = Some(String::from("not renamed"));
+        let update = update_file(path, container, datetime, flags, text)?;
+        if update.written {
+            index_entry.iter_mut().for_each(|entry| entry.sha256 = None);
+        }
+        record.status = update.status(flags.dry_run);
+        add_to_index(index, index_entry, path)?;
+        return Ok(record);
+    }
+
+    // Only files that are renamed are counted, so this is the file's number if it is
+    let context = TemplateContext {
+        path,
+        datetime,
+        source: metadata.creation_date.source,
+        counter: *counter + 1,
+        make: metadata.device.make.as_deref(),
+        model: metadata.device.model.as_deref(),
+    };
+    let name_match = if flags.force {
+        NameMatch::Unmatched
+    } else {
+        settings.template.match_name(&context)
+    };
+    let quarantine = action == Some(DuplicateAction::Quarantine);
+    // The directory to put the file in, when it isn't to stay where it is
+    let dest_dir = if quarantine {
+        flags.quarantine.clone()
+    } else {
+        flags
+            .dest
+            .as_ref()
+            .map(|root| root.join(flags.dest_template.render(&context)))
+            .filter(|dir| !is_in(dir, path))
+    };
+    let new_path = match name_match {
+        // Quarantined files keep their name
+        _ if quarantine => path.to_path_buf(),
+        NameMatch::Current if dest_dir.is_some() => path.to_path_buf(),
+        NameMatch::Current => {
+            if text {
+                println!("Skipping {}: already named", path.display());
+            }
+            record.message = Some(String::from("already named"));
+            let update = update_file(path, container, datetime, flags, text)?;
+            if update.written {
+                index_entry.iter_mut().for_each(|entry| entry.sha256 = None);
+            }
+            record.status = update.status(flags.dry_run);
+            add_to_index(index, index_entry, path)?;
+            return Ok(record);
+        }
+        NameMatch::Stale(original) => {
+            let original_path = path.with_file_name(original);
+            settings.template.new_path(&TemplateContext {
+                path: &original_path,
+                ..context
             })
-    })
+        }
+        NameMatch::Unmatched => settings.template.new_path(&context),
+    };
+    let new_path = match &dest_dir {
+        Some(dir) => {
+            if !flags.dry_run {
+                fs::create_dir_all(dir)?;
+            }
+            dir.join(new_path.file_name().unwrap_or_default())
+        }
+        None => new_path,
+    };
+    // Duplicates are linked to the file they duplicate, or moved aside
+    let (from, mode, on_conflict) = match (&duplicate, action) {
+        (Some(original), Some(DuplicateAction::HardLink)) => {
+            if *original == path::absolute(&new_path)? {
+                return skip_duplicate(path, original, text, record);
+            }
+            (original.as_path(), Mode::HardLink, flags.on_conflict)
+        }
+        (_, Some(DuplicateAction::Quarantine)) => (path, flags.mode, ConflictPolicy::Suffix),
+        _ => (path, flags.mode, flags.on_conflict),
+    };
+    let transferred = rename::transfer(from, &new_path, mode, on_conflict, flags.dry_run)?;
+    let transferred = match transferred {
+        Some(transferred) => transferred,
+        None => {
+            if text {
+                println!(
+                    "Skipping {}: {} already exists",
+                    path.display(),
+                    new_path.display()
+                );
+            }
+            record.message = Some(format!("{} already exists", new_path.display()));
+            return Ok(record);
+        }
+    };
+    *counter += 1;
+    record.status = if flags.dry_run {
+        Status::WouldRename
+    } else {
+        Status::Renamed
+    };
+    let new_path = &transferred.path;
+    record.new_path = Some(new_path.clone());
+    if text {
+        println!(
+            "{} -> {} ({}, from {}, {} time{}{}{}{}{})",
+            path.display(),
+            new_path.display(),
+            datetime.format(&Rfc2822).unwrap(),
+            source,
+            metadata.creation_date.clock,
+            if source.is_low_confidence() {
+                ", low confidence"
+            } else {
+                ""
+            },
+            match settings.rule {
+                Some(rule) => format!(", rule {}", rule),
+                None => String::new(),
+            },
+            match correction {
+                Some(correction) => format!(
+                    ", clock corrected by {:+}s",
+                    correction.whole_milliseconds() as f64 / 1000.
+                ),
+                None => String::new(),
+            },
+            match &duplicate {
+                Some(original) => format!(", duplicate of {}", original.display()),
+                None => String::new(),
+            },
+            match mode {
+                // Moves copy the file when it's going to another file system
+                Mode::Move if transferred.checksums.is_some() => ", copied and verified",
+                Mode::Rename | Mode::Move => "",
+                // Nothing is copied with a dry run
+                Mode::Copy if flags.dry_run => ", copied",
+                Mode::Copy => ", copied and verified",
+                Mode::HardLink => ", hard linked",
+                Mode::Reflink => ", reflinked",
+            }
+        );
+    }
+    // The file is replaced by the link, unless the original is to be kept
+    let linked = mode != flags.mode;
+    if linked && !flags.mode.keeps_original() && !flags.dry_run {
+        fs::remove_file(path)?;
+    }
+    let mut journal_entry = None;
+    if !flags.dry_run {
+        let entry = JournalEntry {
+            old_path: path::absolute(path)?,
+            new_path: path::absolute(new_path)?,
+            renamed_at: OffsetDateTime::now_utc(),
+            creation_date: datetime,
+            source,
+            low_confidence: source.is_low_confidence(),
+            offset: record.offset.unwrap_or_default(),
+            // Undoing a link that replaced the file moves it back
+            mode: if linked && flags.mode.keeps_original() {
+                Mode::HardLink
+            } else {
+                flags.mode
+            },
+        };
+        let journal_path = match &flags.journal {
+            Some(journal_path) => journal_path.clone(),
+            None => journal::default_journal_path(entry.new_path.parent().unwrap()),
+        };
+        journal::append(&journal_path, &entry)?;
+        journal_entry = Some(entry);
+    }
+    // Before a dry run the file is still at its old path
+    let path = if flags.dry_run { path } else { new_path };
+    let updated = if quarantine {
+        Ok(Update::default())
+    } else {
+        update_file(path, container, datetime, flags, text)
+    };
+    if let (Some(entry), Some(checksums)) = (journal_entry, transferred.checksums) {
+        let manifest_path = match &flags.manifest {
+            Some(manifest_path) => manifest_path.clone(),
+            None => manifest::default_manifest_path(entry.new_path.parent().unwrap()),
+        };
+        let mut manifest_entry = ManifestEntry::new(entry.new_path, entry.old_path, &checksums);
+        // Copies are hashed again if the date may have been written into them, so
+        // the manifest has both the verified copy and what's now on disk
+        if !matches!(updated, Ok(Update { written: false, .. })) {
+            let (hash, _) = rename::hash_file(&manifest_entry.path)?;
+            manifest_entry = manifest_entry.patched(&hash);
+        }
+        manifest::append(&manifest_path, &manifest_entry)?;
+    }
+    if updated?.written {
+        index_entry.iter_mut().for_each(|entry| entry.sha256 = None);
+    }
+    if !quarantine {
+        add_to_index(index, index_entry, path)?;
+    }
+
+    Ok(record)
 }
 
-fn generate_new_path(path: &Path, creation_date: OffsetDateTime) -> PathBuf {
-    // prepend a timestamp to the file
-    let mut file_name = OsString::from(creation_date.unix_timestamp().to_string());
-    file_name.push(" ");
-    file_name.push(path.file_name().unwrap()); // file_name should exist at this point
-    path.with_file_name(file_name)
+/// What [`update_file`] did to a file.
+#[derive(Debug, Clone, Copy, Default)]
+struct Update {
+    /// The metadata or times of the file were set, or would be with a dry run
+    changed: bool,
+    /// The contents of the file were written
+    written: bool,
 }
 
-fn f32_to_i32(x: f32) -> Option<i32> {
-    (x == (x as i32) as f32).then(|| x as i32)
+impl Update {
+    /// The status of a file that isn't renamed.
+    fn status(self, dry_run: bool) -> Status {
+        match (self.changed, dry_run) {
+            (false, _) => Status::Skipped,
+            (true, false) => Status::Updated,
+            (true, true) => Status::WouldUpdate,
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Write `datetime` into the metadata of the file at `path` with
+/// `--write-metadata`, and set its times with `--touch`.
+fn update_file(
+    path: &Path,
+    container: Container,
+    datetime: OffsetDateTime,
+    flags: &Flags,
+    text: bool,
+) -> Result<Update, Error> {
+    let written = write_metadata(path, container, datetime, flags, text)?;
+    let mut update = Update {
+        changed: written,
+        written: written && !flags.dry_run,
+    };
+    if !flags.touch {
+        return Ok(update);
+    }
+    let modified = fs::metadata(path)?
+        .modified()
+        .ok()
+        .map(OffsetDateTime::from);
+    if modified == Some(datetime) && !flags.touch_atime {
+        return Ok(update);
+    }
+    if !flags.dry_run {
+        rename::touch(path, datetime, flags.touch_atime)?;
+    }
+    if text {
+        println!(
+            "{} the {} of {} to {}",
+            if flags.dry_run { "Would set" } else { "Set" },
+            if flags.touch_atime {
+                "modification and access times"
+            } else {
+                "modification time"
+            },
+            path.display(),
+            datetime.format(&Rfc2822).unwrap()
+        );
+    }
+    update.changed = true;
+    Ok(update)
+}
 
-    #[test]
-    fn test_generate_new_path() {
-        let path = Path::new("folder/IMG_4792.mkv");
-        let datetime = OffsetDateTime::from_unix_timestamp(1681265941).unwrap();
-        let new_path = generate_new_path(path, datetime);
-        let expected_path = Path::new("folder/1681265941 IMG_4792.mkv");
-        assert_eq!(new_path, expected_path);
+/// Write `datetime` into the metadata of the file at `path` with
+/// `--write-metadata`, returning whether it was changed, or would be with a dry
+/// run.
+fn write_metadata(
+    path: &Path,
+    container: Container,
+    datetime: OffsetDateTime,
+    flags: &Flags,
+    text: bool,
+) -> Result<bool, Error> {
+    if !flags.write_metadata {
+        return Ok(false);
+    }
+    let patches = patch::write_creation_date(path, container, datetime, flags.dry_run)?;
+    if patches.is_empty() {
+        return Ok(false);
     }
+    if text {
+        let mut fields = Vec::new();
+        for patch in &patches {
+            if !fields.contains(&patch.field) {
+                fields.push(patch.field);
+            }
+        }
+        println!(
+            "{} the creation date into {} ({})",
+            if flags.dry_run {
+                "Would write"
+            } else {
+                "Wrote"
+            },
+            path.display(),
+            fields.join(", ")
+        );
+    }
+    Ok(true)
+}
+
+/// Leave a file alone as it duplicates `original`.
+fn skip_duplicate(
+    path: &Path,
+    original: &Path,
+    text: bool,
+    mut record: Record,
+) -> Result<Record, Error> {
+    if text {
+        println!(
+            "Skipping {}: duplicate of {}",
+            path.display(),
+            original.display()
+        );
+    }
+    record.message = Some(format!("duplicate of {}", original.display()));
+    Ok(record)
+}
+
+/// Add a file to the index of files to find duplicates of, at `path` where it
+/// ended up.
+fn add_to_index(
+    index: Option<&mut Index>,
+    entry: Option<IndexEntry>,
+    path: &Path,
+) -> Result<(), Error> {
+    if let (Some(index), Some(entry)) = (index, entry) {
+        index.insert(IndexEntry {
+            path: path::absolute(path)?,
+            ..entry
+        })?;
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` is in the directory `dir`.
+fn is_in(dir: &Path, path: &Path) -> bool {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(dir), fs::canonicalize(parent)) {
+        (Ok(dir), Ok(parent)) => dir == parent,
+        _ => false,
+    }
+}
+
+/// Apply the offset or time zone in `settings` to a date read from the file at
+/// `path`.
+///
+/// Only local times are adjusted. Absolute times are already correct, and are just
+/// shown in the time zone, if there is one.
+fn adjust(path: &Path, date: &CreationDate, settings: &Settings<'_>) -> OffsetDateTime {
+    let datetime = date.datetime;
+    let zone = match (date.clock, settings.zone) {
+        (Clock::Absolute, Some(zone)) => return datetime.to_offset(zone.offset_at(datetime)),
+        (Clock::Absolute, None) => return datetime,
+        (Clock::Local, Some(zone)) => zone,
+        (Clock::Local, None) => return datetime + settings.offset.unwrap_or_default(),
+    };
+    let local = PrimitiveDateTime::new(datetime.date(), datetime.time());
+    let resolved = zone.resolve(local);
+    match resolved {
+        LocalTime::Unique(_) => (),
+        LocalTime::Ambiguous { earlier, later } => eprintln!(
+            "Warning: the time of {}, {}, happened twice in {}, using the earlier {} rather than {}",
+            path.display(),
+            local.format(LOCAL_TIME_FORMAT).unwrap(),
+            zone,
+            earlier.format(&Rfc2822).unwrap(),
+            later.format(&Rfc2822).unwrap()
+        ),
+        LocalTime::Skipped(resolved) => eprintln!(
+            "Warning: the time of {}, {}, was skipped by a clock change in {}, using {}",
+            path.display(),
+            local.format(LOCAL_TIME_FORMAT).unwrap(),
+            zone,
+            resolved.format(&Rfc2822).unwrap()
+        ),
+    }
+    resolved.earliest()
+}
+
+/// Warn when the file name holds a date that doesn't match the creation date, as
+/// one of them is likely wrong.
+fn check_filename_date(path: &Path, creation_date: &CreationDate) {
+    if creation_date.source.kind() == SourceKind::Filename {
+        return;
+    }
+    let name = path.file_name().and_then(|name| name.to_str());
+    if let Some(named) = name.and_then(filename::parse_date) {
+        if !named.agrees_with(creation_date.datetime) {
+            let named_date = if named.has_time {
+                named.datetime.format(LOCAL_TIME_FORMAT)
+            } else {
+                named
+                    .datetime
+                    .format(format_description!("[year]-[month]-[day]"))
+            };
+            eprintln!(
+                "Warning: the name of {} suggests {}, but its {} creation date is {}",
+                path.display(),
+                named_date.unwrap(),
+                creation_date.source,
+                creation_date.datetime.format(&Rfc2822).unwrap()
+            );
+        }
+    }
+}
+
+fn f32_to_i32(x: f32) -> Option<i32> {
+    (x == (x as i32) as f32).then_some(x as i32)
 }
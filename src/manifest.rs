@@ -0,0 +1,112 @@
+//! A record of verified copies, with the hashes of each copy and its original.
+//!
+//! The manifest is a JSON Lines file with one [`ManifestEntry`] per copy, so that
+//! copies can be checked again later, e.g. before wiping the card they came from.
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::rename::{to_hex, Checksums};
+use crate::Error;
+
+/// The name of the manifest written next to copied files when no other location
+/// is chosen.
+pub const DEFAULT_MANIFEST_NAME: &str = ".mkv-rename-manifest.jsonl";
+
+/// A single verified copy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The absolute path of the copy
+    pub path: PathBuf,
+    /// The absolute path of the file it was copied from
+    pub original: PathBuf,
+    /// The size of the file in bytes
+    pub size: u64,
+    /// The SHA-256 hash of the original as it was read, in hex
+    pub original_sha256: String,
+    /// The SHA-256 hash of the copy as it was read back from disk, in hex
+    pub copy_sha256: String,
+    /// The SHA-256 hash of the copy after `--write-metadata` wrote the creation
+    /// date into it, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patched_sha256: Option<String>,
+    /// When the copy was made
+    #[serde(with = "time::serde::rfc3339")]
+    pub copied_at: OffsetDateTime,
+}
+
+impl ManifestEntry {
+    pub fn new(path: PathBuf, original: PathBuf, checksums: &Checksums) -> Self {
+        ManifestEntry {
+            path,
+            original,
+            size: checksums.size,
+            original_sha256: to_hex(&checksums.original),
+            copy_sha256: to_hex(&checksums.copy),
+            patched_sha256: None,
+            copied_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    /// Record `hash` as the hash of the copy once the creation date was written
+    /// into it.
+    pub fn patched(self, hash: &[u8; 32]) -> Self {
+        ManifestEntry {
+            patched_sha256: Some(to_hex(hash)),
+            ..self
+        }
+    }
+}
+
+/// The manifest used for copies in `dir` when no other location is chosen.
+pub fn default_manifest_path(dir: &Path) -> PathBuf {
+    dir.join(DEFAULT_MANIFEST_NAME)
+}
+
+/// Append `entry` to the manifest at `path`, creating it if necessary.
+pub fn append(path: &Path, entry: &ManifestEntry) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+    line.push(b'\n');
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let checksums = Checksums {
+            original: [0xab; 32],
+            copy: [0xab; 32],
+            size: 1024,
+        };
+        let entry = ManifestEntry::new(
+            PathBuf::from("/archive/2023/1681265941 IMG_4792.mov"),
+            PathBuf::from("/media/card/DCIM/IMG_4792.mov"),
+            &checksums,
+        );
+        assert_eq!(entry.original_sha256, "ab".repeat(32));
+        assert_eq!(entry.copy_sha256, entry.original_sha256);
+
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(r#""size":1024"#));
+        assert!(!json.contains("patched_sha256"));
+        assert_eq!(serde_json::from_str::<ManifestEntry>(&json).unwrap(), entry);
+
+        // The copy keeps its verified hash once the date is written into it
+        let entry = entry.patched(&[0xcd; 32]);
+        assert_eq!(entry.copy_sha256, "ab".repeat(32));
+        assert_eq!(entry.patched_sha256, Some("cd".repeat(32)));
+        let json = serde_json::to_string(&entry).unwrap();
+        assert_eq!(serde_json::from_str::<ManifestEntry>(&json).unwrap(), entry);
+    }
+}
@@ -0,0 +1,554 @@
+//! Writing a corrected creation date back into the metadata of a video, in place.
+//!
+//! Only fields of a fixed size are overwritten, so nothing in the file moves and
+//! nothing needs to be remuxed: the creation and modification times in the MPEG4
+//! movie, track and media headers, and the Matroska segment `DateUTC`, which is
+//! added in the space of a `Void` element if there isn't one. The MPEG4 header
+//! times are written in UTC, as the standard says.
+//!
+//! The old bytes are saved in an undo file next to the video before anything is
+//! written, so that a run interrupted half way through can be put right with
+//! [`restore_interrupted`].
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use time::macros::datetime;
+use time::OffsetDateTime;
+
+use crate::{bmff, ebml, Container, Error, MP4_EPOCH_OFFSET};
+
+/// The time Matroska dates count from.
+const MATROSKA_EPOCH: OffsetDateTime = datetime!(2001-01-01 0:00 UTC);
+
+const INFO: u32 = 0x1549_A966;
+const DATE_UTC: u32 = 0x4461;
+const VOID: u32 = 0xEC;
+const CRC_32: u32 = 0xBF;
+
+/// The segment info is read into memory, and should never be anywhere near this.
+const MAX_INFO_SIZE: u64 = 1 << 20;
+
+/// Added to the name of a video, after a leading dot, for its undo file.
+const UNDO_SUFFIX: &str = ".mkv-rename-undo.json";
+
+/// A change to the bytes of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patch {
+    pub offset: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+    /// The name of the box or element changed, e.g. `mvhd` or `DateUTC`
+    pub field: &'static str,
+}
+
+/// The old bytes at an offset in a video, as saved in its undo file.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct UndoEntry {
+    offset: u64,
+    old: Vec<u8>,
+}
+
+/// Write `datetime` as the creation date in the metadata of the file at `path`,
+/// of type `container`.
+///
+/// Returns the changes made, or with `dry_run` the changes that would be made,
+/// which are none if the file already has the date. The file is checked before
+/// and after the changes are written, and the old bytes are put back if it
+/// doesn't read back with the new date. The old bytes are synced to the undo
+/// file before the first change, which is removed once the file has been checked
+/// or put back.
+pub fn write_creation_date(
+    path: &Path,
+    container: Container,
+    datetime: OffsetDateTime,
+    dry_run: bool,
+) -> Result<Vec<Patch>, Error> {
+    let patches = plan(&mut BufReader::new(File::open(path)?), container, datetime)?;
+    if dry_run || patches.is_empty() {
+        return Ok(patches);
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    for patch in &patches {
+        if read_at(&mut file, patch.offset, patch.old.len())? != patch.old {
+            return Err(Error::WriteMetadata(String::from(
+                "the file changed while it was being read",
+            )));
+        }
+    }
+    let undo_path = undo_path(path);
+    write_undo(&undo_path, &patches)?;
+    let result = write_patches(&mut file, &patches, |patch| &patch.new)
+        .map_err(Error::from)
+        .and_then(|()| {
+            // Nothing should be left to change if the file reads back with the date
+            let remaining = plan(&mut BufReader::new(File::open(path)?), container, datetime)?;
+            match remaining.is_empty() {
+                true => Ok(()),
+                false => Err(Error::WriteMetadata(String::from(
+                    "the file didn't read back with the new date, so it was restored",
+                ))),
+            }
+        });
+    if result.is_err() {
+        write_patches(&mut file, &patches, |patch| &patch.old)?;
+    }
+    fs::remove_file(&undo_path)?;
+    result.map(|()| patches)
+}
+
+/// Put back the old bytes saved in the undo file of the file at `path`, if an
+/// earlier run was interrupted while writing into it.
+///
+/// Returns whether there was anything to put back.
+pub fn restore_interrupted(path: &Path) -> Result<bool, Error> {
+    let undo_path = undo_path(path);
+    let contents = match fs::read(&undo_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+    let entries: Vec<UndoEntry> = serde_json::from_slice(&contents).map_err(|err| {
+        Error::WriteMetadata(format!(
+            "invalid undo file {}: {}",
+            undo_path.display(),
+            err
+        ))
+    })?;
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    for entry in &entries {
+        file.seek(SeekFrom::Start(entry.offset))?;
+        file.write_all(&entry.old)?;
+    }
+    file.sync_data()?;
+    fs::remove_file(&undo_path)?;
+    Ok(true)
+}
+
+/// The undo file of the video at `path`, e.g. `.IMG_4818.mov.mkv-rename-undo.json`
+/// next to `IMG_4818.mov`.
+fn undo_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(UNDO_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Save the old bytes of `patches` in the undo file at `path`, synced to disk.
+///
+/// The file is written under a temporary name and renamed into place, so that
+/// it's never left half written.
+fn write_undo(path: &Path, patches: &[Patch]) -> Result<(), Error> {
+    let entries: Vec<_> = patches
+        .iter()
+        .map(|patch| UndoEntry {
+            offset: patch.offset,
+            old: patch.old.clone(),
+        })
+        .collect();
+    let contents = serde_json::to_vec(&entries).map_err(io::Error::from)?;
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // The rename is only durable once the directory is synced too
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// The changes that give the file in `reader` the creation date `datetime`.
+fn plan<R: Read + Seek>(
+    reader: &mut R,
+    container: Container,
+    datetime: OffsetDateTime,
+) -> Result<Vec<Patch>, Error> {
+    let patches = match container {
+        Container::Mp4 => plan_mp4(reader, datetime)?,
+        Container::Matroska => plan_matroska(reader, datetime)?,
+        Container::Jpeg | Container::Heif | Container::Tiff => {
+            return Err(Error::WriteMetadata(format!(
+                "writing into {} files isn't supported",
+                container
+            )))
+        }
+    };
+    Ok(patches
+        .into_iter()
+        .filter(|patch| patch.old != patch.new)
+        .collect())
+}
+
+/// The changes to the creation and modification times in the movie header, and
+/// the track and media header of each track.
+fn plan_mp4<R: Read + Seek>(reader: &mut R, datetime: OffsetDateTime) -> Result<Vec<Patch>, Error> {
+    let no_header = || Error::WriteMetadata(String::from("the file has no movie header"));
+    let moov = bmff::find_path(reader, &[b"moov"])?.ok_or_else(no_header)?;
+    let mvhd = bmff::find(reader, moov.start, moov.end, b"mvhd")?.ok_or_else(no_header)?;
+    let seconds = datetime.unix_timestamp() + MP4_EPOCH_OFFSET;
+
+    let mut patches = Vec::new();
+    header_patches(reader, mvhd, "mvhd", seconds, &mut patches)?;
+    let mut offset = moov.start;
+    while let Some(trak) = bmff::find(reader, offset, moov.end, b"trak")? {
+        offset = trak.end;
+        if let Some(tkhd) = bmff::find(reader, trak.start, trak.end, b"tkhd")? {
+            header_patches(reader, tkhd, "tkhd", seconds, &mut patches)?;
+        }
+        if let Some(mdia) = bmff::find(reader, trak.start, trak.end, b"mdia")? {
+            if let Some(mdhd) = bmff::find(reader, mdia.start, mdia.end, b"mdhd")? {
+                header_patches(reader, mdhd, "mdhd", seconds, &mut patches)?;
+            }
+        }
+    }
+    Ok(patches)
+}
+
+/// The changes to the creation and modification times at the start of a `mvhd`,
+/// `tkhd` or `mdhd` full box, as seconds since 1904.
+fn header_patches<R: Read + Seek>(
+    reader: &mut R,
+    header: bmff::BoxHeader,
+    field: &'static str,
+    seconds: i64,
+    patches: &mut Vec<Patch>,
+) -> Result<(), Error> {
+    reader.seek(SeekFrom::Start(header.start))?;
+    let version = bmff::read_u8(reader)?;
+    let new = if version == 1 {
+        (seconds as u64).to_be_bytes().to_vec()
+    } else {
+        u32::try_from(seconds)
+            .map_err(|_| {
+                Error::WriteMetadata(format!("the date is too late for the {} box", field))
+            })?
+            .to_be_bytes()
+            .to_vec()
+    };
+    let width = new.len() as u64;
+    if header.end - header.start < 4 + 2 * width {
+        return Err(Error::WriteMetadata(format!(
+            "the {} box is too short",
+            field
+        )));
+    }
+    for offset in [header.start + 4, header.start + 4 + width] {
+        patches.push(Patch {
+            offset,
+            old: read_at(reader, offset, new.len())?,
+            new: new.clone(),
+            field,
+        });
+    }
+    Ok(())
+}
+
+/// The change to the `DateUTC` in the segment info, or to a `Void` to make room
+/// for one, and to the CRC-32 of the segment info if it has one.
+fn plan_matroska<R: Read + Seek>(
+    reader: &mut R,
+    datetime: OffsetDateTime,
+) -> Result<Vec<Patch>, Error> {
+    let no_info = || Error::WriteMetadata(String::from("the file has no segment info"));
+    let end = reader.seek(SeekFrom::End(0))?;
+    let segment = ebml::find(reader, 0, end, ebml::SEGMENT)?.ok_or_else(no_info)?;
+    let info = match ebml::find(reader, segment.start, segment.end, INFO)? {
+        Some(info) if !info.unknown_size && info.end - info.start <= MAX_INFO_SIZE => info,
+        _ => return Err(no_info()),
+    };
+    let nanoseconds = i64::try_from((datetime - MATROSKA_EPOCH).whole_nanoseconds())
+        .map_err(|_| Error::WriteMetadata(String::from("the date is out of range")))?;
+    let value = nanoseconds.to_be_bytes();
+
+    // Work on a copy of the segment info, so the CRC-32 can be worked out after
+    let mut data = read_at(reader, info.start, (info.end - info.start) as usize)?;
+    let original = data.clone();
+    let len = data.len() as u64;
+    let mut date_utc = None;
+    let mut voids = Vec::new();
+    let mut crc = None;
+    let mut offset = 0;
+    let mut children = Cursor::new(&original);
+    while let Some(child) = ebml::read_header(&mut children, offset, len)? {
+        if child.end > len {
+            return Err(ebml::invalid_data("element extends past its parent").into());
+        }
+        match child.id {
+            DATE_UTC => date_utc = Some(child),
+            VOID => voids.push((offset, child)),
+            CRC_32 => crc = Some(child),
+            _ => (),
+        }
+        offset = child.end;
+    }
+
+    let (start, end) = match date_utc {
+        Some(date_utc) if date_utc.end - date_utc.start == 8 => {
+            data[date_utc.start as usize..date_utc.end as usize].copy_from_slice(&value);
+            (date_utc.start, date_utc.end)
+        }
+        Some(_) => {
+            return Err(Error::WriteMetadata(String::from(
+                "the DateUTC element has an unexpected size",
+            )))
+        }
+        None => {
+            // DateUTC takes 11 bytes with its header, and what's left of the void
+            // needs to be big enough for a void of its own
+            let room = |(offset, void): &(u64, ebml::ElementHeader)| void.end - offset;
+            let (offset, void) = voids
+                .iter()
+                .find(|void| room(void) == 11 || room(void) >= 13)
+                .ok_or_else(|| {
+                    Error::WriteMetadata(String::from(
+                        "there's no room in the segment info to add a DateUTC element",
+                    ))
+                })?;
+            let mut element = vec![0x44, 0x61, 0x88];
+            element.extend_from_slice(&value);
+            element.extend(void_element(void.end - offset - 11));
+            data[*offset as usize..void.end as usize].copy_from_slice(&element);
+            (*offset, void.end)
+        }
+    };
+    let mut patches = vec![Patch {
+        offset: info.start + start,
+        old: original[start as usize..end as usize].to_vec(),
+        new: data[start as usize..end as usize].to_vec(),
+        field: "DateUTC",
+    }];
+
+    // The CRC-32 covers everything after it in its parent
+    if let Some(crc) = crc.filter(|crc| crc.end - crc.start == 4) {
+        let (start, end) = (crc.start as usize, crc.end as usize);
+        patches.push(Patch {
+            offset: info.start + crc.start,
+            old: original[start..end].to_vec(),
+            new: crc32(&data[end..]).to_le_bytes().to_vec(),
+            field: "CRC-32",
+        });
+    }
+    Ok(patches)
+}
+
+/// A `Void` element taking up `len` bytes with its header, or nothing if `len`
+/// is zero.
+fn void_element(len: u64) -> Vec<u8> {
+    let mut element = match len {
+        0 => return Vec::new(),
+        // A one byte size can't be all ones, which means unknown
+        2..=128 => vec![VOID as u8, 0x80 | (len - 2) as u8],
+        _ => {
+            let mut element = vec![VOID as u8, 0x01];
+            element.extend_from_slice(&(len - 9).to_be_bytes()[1..]);
+            element
+        }
+    };
+    element.resize(len as usize, 0);
+    element
+}
+
+/// The CRC-32 of `data`, as used by Matroska (and zip, PNG, etc).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_at<R: Read + Seek>(reader: &mut R, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Write the `old` or `new` bytes of each patch, and sync them to disk.
+fn write_patches(
+    file: &mut File,
+    patches: &[Patch],
+    bytes: impl Fn(&Patch) -> &[u8],
+) -> io::Result<()> {
+    for patch in patches {
+        file.seek(SeekFrom::Start(patch.offset))?;
+        file.write_all(bytes(patch))?;
+    }
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxed(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn element(id: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut data = id.to_vec();
+        data.push(0x80 | payload.len() as u8);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn apply(data: &mut [u8], patches: &[Patch]) {
+        for patch in patches {
+            let offset = patch.offset as usize;
+            assert_eq!(&data[offset..offset + patch.old.len()], patch.old);
+            data[offset..offset + patch.new.len()].copy_from_slice(&patch.new);
+        }
+    }
+
+    #[test]
+    fn test_plan_mp4() {
+        let datetime = datetime!(2023-04-12 04:39:01 UTC);
+        let mut mvhd = vec![0; 4];
+        mvhd.extend_from_slice(&[0xFF; 8]);
+        mvhd.extend_from_slice(&[0; 8]);
+        let mut mdhd = vec![1, 0, 0, 0];
+        mdhd.extend_from_slice(&[0xFF; 16]);
+        mdhd.extend_from_slice(&[0; 12]);
+        let trak = boxed(b"trak", &boxed(b"mdia", &boxed(b"mdhd", &mdhd)));
+        let mut moov = boxed(b"mvhd", &mvhd);
+        moov.extend(trak);
+        let mut file = boxed(b"ftyp", b"qt  \0\0\0\0");
+        file.extend(boxed(b"moov", &moov));
+
+        let patches = plan(&mut Cursor::new(&file), Container::Mp4, datetime).unwrap();
+        let fields: Vec<_> = patches.iter().map(|patch| patch.field).collect();
+        assert_eq!(fields, ["mvhd", "mvhd", "mdhd", "mdhd"]);
+        apply(&mut file, &patches);
+
+        let expected = (datetime.unix_timestamp() + MP4_EPOCH_OFFSET) as u64;
+        for patch in &patches {
+            let mut time = [0; 8];
+            time[8 - patch.new.len()..].copy_from_slice(&patch.new);
+            assert_eq!(u64::from_be_bytes(time), expected, "{}", patch.field);
+        }
+        assert_eq!(
+            plan(&mut Cursor::new(&file), Container::Mp4, datetime).unwrap(),
+            []
+        );
+    }
+
+    #[test]
+    fn test_write_creation_date() {
+        let dir = std::env::temp_dir().join(format!("mkv-rename-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("IMG_0001.MOV");
+        let mut mvhd = vec![0; 4];
+        mvhd.extend_from_slice(&[0xFF; 8]);
+        let mut original = boxed(b"ftyp", b"qt  \0\0\0\0");
+        original.extend(boxed(b"moov", &boxed(b"mvhd", &mvhd)));
+        fs::write(&path, &original).unwrap();
+
+        let datetime = datetime!(2023-04-12 04:39:01 UTC);
+        let patches = write_creation_date(&path, Container::Mp4, datetime, false).unwrap();
+        assert_eq!(patches.len(), 2);
+        assert!(!undo_path(&path).exists());
+        assert!(!restore_interrupted(&path).unwrap());
+
+        // As if the run had been interrupted after the first change
+        write_undo(&undo_path(&path), &patches).unwrap();
+        assert!(restore_interrupted(&path).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(!undo_path(&path).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plan_matroska() {
+        let datetime = datetime!(2023-04-12 04:39:01.5 UTC);
+        let matroska = |info: &[u8]| {
+            let mut file = element(&[0x1A, 0x45, 0xDF, 0xA3], &[]);
+            file.extend(element(
+                &[0x18, 0x53, 0x80, 0x67],
+                &element(&[0x15, 0x49, 0xA9, 0x66], info),
+            ));
+            file
+        };
+        let read_date = |file: &[u8]| {
+            let mut children = Cursor::new(file);
+            let info = ebml::find(&mut children, 0, file.len() as u64, ebml::SEGMENT)
+                .unwrap()
+                .and_then(|segment| {
+                    ebml::find(&mut children, segment.start, segment.end, INFO).unwrap()
+                })
+                .unwrap();
+            let date = ebml::find(&mut children, info.start, info.end, DATE_UTC)
+                .unwrap()
+                .unwrap();
+            let value = read_at(&mut children, date.start, 8).unwrap();
+            MATROSKA_EPOCH
+                + time::Duration::nanoseconds(i64::from_be_bytes(value.try_into().unwrap()))
+        };
+
+        // An existing date is replaced, and the CRC-32 updated
+        let mut info = element(&[0xBF], &[0; 4]);
+        info.extend(element(&[0x44, 0x61], &[0; 8]));
+        let mut file = matroska(&info);
+        let patches = plan(&mut Cursor::new(&file), Container::Matroska, datetime).unwrap();
+        apply(&mut file, &patches);
+        assert_eq!(read_date(&file), datetime);
+        let crc = &patches[1];
+        assert_eq!(crc.field, "CRC-32");
+        let date_element = &file[crc.offset as usize + 4..];
+        assert_eq!(crc.new, crc32(&date_element[..11]).to_le_bytes());
+
+        // A date is added in place of a void, leaving a smaller void behind
+        let mut file = matroska(&element(&[0xEC], &[0; 20]));
+        let patches = plan(&mut Cursor::new(&file), Container::Matroska, datetime).unwrap();
+        apply(&mut file, &patches);
+        assert_eq!(read_date(&file), datetime);
+        assert_eq!(&file[file.len() - 11..file.len() - 9], [0xEC, 0x89]);
+        assert_eq!(
+            plan(&mut Cursor::new(&file), Container::Matroska, datetime).unwrap(),
+            []
+        );
+
+        // A void one byte too big can't be split, so nothing is changed
+        let file = matroska(&element(&[0xEC], &[0; 10]));
+        assert!(matches!(
+            plan(&mut Cursor::new(&file), Container::Matroska, datetime),
+            Err(Error::WriteMetadata(_))
+        ));
+    }
+
+    #[test]
+    fn test_void_element() {
+        assert!(void_element(0).is_empty());
+        assert_eq!(void_element(2), [0xEC, 0x80]);
+        assert_eq!(void_element(128).len(), 128);
+        assert_eq!(void_element(128)[1], 0xFE);
+        let void = void_element(200);
+        assert_eq!(void.len(), 200);
+        assert_eq!(void[..9], [0xEC, 0x01, 0, 0, 0, 0, 0, 0, 191]);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,315 @@
+//! Reading QuickTime metadata (`moov/meta` `keys` and `ilst`), as written by
+//! iPhones and other Apple devices, and the encoder of MOV and MP4 files, which
+//! the `mp4` crate doesn't parse. The movie, track and media headers and the brand
+//! are parsed by the crate.
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use mp4::{BoxType, FtypBox, MoovBox, ReadBox};
+
+use crate::bmff;
+
+/// The `data` type indicator for UTF-8 strings.
+const TYPE_UTF8: u32 = 1;
+
+/// Keys with more entries than this are assumed to be corrupt.
+const MAX_KEYS: u32 = 1000;
+
+/// The type of the encoder item in `ilst`, and of the encoder string in `udta`.
+const ENCODER: [u8; 4] = *b"\xA9too";
+
+/// Read the string metadata items of a QuickTime file as `(key, value)` pairs,
+/// e.g. `("com.apple.quicktime.creationdate", "2023-04-12T14:39:01+1000")`.
+pub(crate) fn read_metadata<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+    let meta = match bmff::find_path(reader, &[b"moov", b"meta"])? {
+        Some(meta) => meta,
+        None => return Ok(Vec::new()),
+    };
+
+    let start = match meta_children(reader, meta)? {
+        Some(start) => start,
+        None => return Ok(Vec::new()),
+    };
+
+    let keys = match bmff::find(reader, start, meta.end, b"keys")? {
+        Some(keys) => read_keys(reader, keys)?,
+        None => return Ok(Vec::new()),
+    };
+    let ilst = match bmff::find(reader, start, meta.end, b"ilst")? {
+        Some(ilst) => ilst,
+        None => return Ok(Vec::new()),
+    };
+
+    // Each item in ilst has the 1-based index of its key as its type
+    let mut items = Vec::new();
+    let mut offset = ilst.start;
+    while let Some(item) = bmff::read_header(reader, offset, ilst.end)? {
+        offset = item.end;
+        let index = u32::from_be_bytes(item.box_type) as usize;
+        let key = match index.checked_sub(1).and_then(|i| keys.get(i)) {
+            Some(key) => key,
+            None => continue,
+        };
+        if let Some(value) = read_string_data(reader, item)? {
+            items.push((key.clone(), value));
+        }
+    }
+    Ok(items)
+}
+
+/// Read the `moov` box, with the movie header and the header of each track.
+///
+/// The `mp4` crate reads every `meta` box as an ISO full box, and fails on the
+/// plain `meta` boxes of QuickTime files, so `meta` and `udta` are left out of
+/// `moov`. They are read by [`read_metadata`] and [`read_encoder`] instead.
+pub(crate) fn read_moov<R: Read + Seek>(reader: &mut R) -> mp4::Result<MoovBox> {
+    let moov =
+        bmff::find_path(reader, &[b"moov"])?.ok_or(mp4::Error::BoxNotFound(BoxType::MoovBox))?;
+    let mut payload = Vec::new();
+    let mut offset = moov.start;
+    while let Some(child) = bmff::read_header(reader, offset, moov.end)? {
+        if !matches!(&child.box_type, b"meta" | b"udta") {
+            reader.seek(SeekFrom::Start(offset))?;
+            read_to_end(reader, child.end - offset, &mut payload)?;
+        }
+        offset = child.end;
+    }
+    parse_box(b"moov", payload)
+}
+
+/// Read the major brand in the `ftyp` box, without trailing spaces.
+pub(crate) fn read_brand<R: Read + Seek>(reader: &mut R) -> mp4::Result<Option<String>> {
+    let ftyp = match bmff::find_path(reader, &[b"ftyp"])? {
+        Some(ftyp) => ftyp,
+        None => return Ok(None),
+    };
+    let mut payload = Vec::new();
+    reader.seek(SeekFrom::Start(ftyp.start))?;
+    read_to_end(reader, ftyp.end - ftyp.start, &mut payload)?;
+    let ftyp: FtypBox = parse_box(b"ftyp", payload)?;
+    let brand = ftyp.major_brand.to_string();
+    Ok(Some(String::from(brand.trim_end_matches([' ', '\0']))))
+}
+
+/// Parse a box of type `box_type` with `payload` using the `mp4` crate.
+fn parse_box<T>(box_type: &[u8; 4], payload: Vec<u8>) -> mp4::Result<T>
+where
+    T: for<'a> ReadBox<&'a mut Cursor<Vec<u8>>>,
+{
+    let size =
+        u32::try_from(payload.len() + 8).map_err(|_| mp4::Error::InvalidData("box too large"))?;
+    let mut data = size.to_be_bytes().to_vec();
+    data.extend_from_slice(box_type);
+    data.extend(payload);
+    let mut reader = Cursor::new(data);
+    let header = mp4::BoxHeader::read(&mut reader)?;
+    T::read_box(&mut reader, header.size)
+}
+
+/// Read the name of the encoder, from the `©too` item in `moov/udta/meta/ilst` as
+/// written by FFmpeg and others, or the `©too` string in `moov/udta`.
+pub(crate) fn read_encoder<R: Read + Seek>(reader: &mut R) -> io::Result<Option<String>> {
+    let udta = match bmff::find_path(reader, &[b"moov", b"udta"])? {
+        Some(udta) => udta,
+        None => return Ok(None),
+    };
+    if let Some(meta) = bmff::find(reader, udta.start, udta.end, b"meta")? {
+        if let Some(start) = meta_children(reader, meta)? {
+            if let Some(ilst) = bmff::find(reader, start, meta.end, b"ilst")? {
+                if let Some(item) = bmff::find(reader, ilst.start, ilst.end, &ENCODER)? {
+                    if let Some(encoder) = read_string_data(reader, item)? {
+                        return Ok(Some(encoder));
+                    }
+                }
+            }
+        }
+    }
+
+    // QuickTime user data strings have a length and language before the text
+    let string = match bmff::find(reader, udta.start, udta.end, &ENCODER)? {
+        Some(string) if string.end - string.start >= 4 => string,
+        _ => return Ok(None),
+    };
+    reader.seek(SeekFrom::Start(string.start))?;
+    let length = u64::from(bmff::read_u16(reader)?).min(string.end - string.start - 4);
+    let _language = bmff::read_u16(reader)?;
+    let mut encoder = vec![0; length as usize];
+    reader.read_exact(&mut encoder)?;
+    Ok(String::from_utf8(encoder).ok())
+}
+
+/// The offset of the first child of a `meta` box.
+///
+/// In QuickTime files meta is a plain box, in ISO files it is a full box with a
+/// version and flags before the children.
+fn meta_children<R: Read + Seek>(reader: &mut R, meta: bmff::BoxHeader) -> io::Result<Option<u64>> {
+    reader.seek(SeekFrom::Start(meta.start))?;
+    match bmff::read_u32(reader) {
+        Ok(0) => Ok(Some(meta.start + 4)),
+        Ok(_) => Ok(Some(meta.start)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Read the names of the `mdta` keys in a `keys` box.
+fn read_keys<R: Read + Seek>(reader: &mut R, keys: bmff::BoxHeader) -> io::Result<Vec<String>> {
+    // Skip the version and flags
+    reader.seek(SeekFrom::Start(keys.start + 4))?;
+    let count = bmff::read_u32(reader)?.min(MAX_KEYS);
+
+    let mut names = Vec::new();
+    let mut offset = keys.start + 8;
+    for _ in 0..count {
+        // Key entries are laid out like boxes, with the namespace as the type
+        let entry = match bmff::read_header(reader, offset, keys.end)? {
+            Some(entry) => entry,
+            None => break,
+        };
+        offset = entry.end;
+        let mut name = Vec::new();
+        reader.seek(SeekFrom::Start(entry.start))?;
+        read_to_end(reader, entry.end - entry.start, &mut name)?;
+        names.push(String::from_utf8_lossy(&name).into_owned());
+    }
+    Ok(names)
+}
+
+/// Read the value of the `data` box in `item`, if it is a string.
+fn read_string_data<R: Read + Seek>(
+    reader: &mut R,
+    item: bmff::BoxHeader,
+) -> io::Result<Option<String>> {
+    let data = match bmff::find(reader, item.start, item.end, b"data")? {
+        Some(data) if data.end - data.start >= 8 => data,
+        _ => return Ok(None),
+    };
+    reader.seek(SeekFrom::Start(data.start))?;
+    let type_indicator = bmff::read_u32(reader)?;
+    let _locale = bmff::read_u32(reader)?;
+    if type_indicator != TYPE_UTF8 {
+        return Ok(None);
+    }
+    let mut value = Vec::new();
+    read_to_end(reader, data.end - data.start - 8, &mut value)?;
+    Ok(String::from_utf8(value).ok())
+}
+
+/// Read exactly `len` bytes into `buf`, without allocating them all up front in
+/// case `len` is corrupt.
+fn read_to_end<R: Read>(reader: &mut R, len: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+    if reader.take(len).read_to_end(buf)? as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn boxed(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = (payload.len() as u32 + 8).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn string_item(index: u32, value: &str) -> Vec<u8> {
+        let mut data = TYPE_UTF8.to_be_bytes().to_vec();
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(value.as_bytes());
+        boxed(&index.to_be_bytes(), &boxed(b"data", &data))
+    }
+
+    #[test]
+    fn test_read_moov() {
+        let mut mvhd = vec![0; 4];
+        mvhd.extend_from_slice(&3_764_155_141u32.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 4]);
+        mvhd.extend_from_slice(&600u32.to_be_bytes());
+        mvhd.extend_from_slice(&1500u32.to_be_bytes());
+        mvhd.extend_from_slice(&[0; 80]);
+        // A QuickTime meta box, which has no version and flags
+        let mut moov = boxed(b"mvhd", &mvhd);
+        moov.extend(boxed(b"meta", &boxed(b"hdlr", &[0; 24])));
+        moov.extend(boxed(b"udta", &boxed(b"meta", &[0; 8])));
+        let mut file = boxed(b"ftyp", b"qt  \0\0\0\0");
+        file.extend(boxed(b"moov", &moov));
+
+        let moov = read_moov(&mut Cursor::new(&file)).unwrap();
+        assert_eq!(moov.mvhd.creation_time, 3_764_155_141);
+        assert_eq!(moov.mvhd.timescale, 600);
+        assert!(moov.traks.is_empty());
+
+        assert!(matches!(
+            read_moov(&mut Cursor::new(boxed(b"ftyp", b"qt  \0\0\0\0"))),
+            Err(mp4::Error::BoxNotFound(BoxType::MoovBox))
+        ));
+    }
+
+    #[test]
+    fn test_read_brand_and_encoder() {
+        let mut ilst_data = TYPE_UTF8.to_be_bytes().to_vec();
+        ilst_data.extend_from_slice(&[0; 4]);
+        ilst_data.extend_from_slice(b"Lavf58.76.100");
+        let ilst = boxed(b"ilst", &boxed(&ENCODER, &boxed(b"data", &ilst_data)));
+        let mut meta = vec![0; 4];
+        meta.extend(ilst);
+        let mut file = boxed(b"ftyp", b"mp42\0\0\0\0");
+        file.extend(boxed(b"moov", &boxed(b"udta", &boxed(b"meta", &meta))));
+        let mut reader = Cursor::new(file);
+        assert_eq!(read_brand(&mut reader).unwrap().as_deref(), Some("mp42"));
+        assert_eq!(
+            read_encoder(&mut reader).unwrap().as_deref(),
+            Some("Lavf58.76.100")
+        );
+
+        // A QuickTime user data string
+        let mut string = 7u16.to_be_bytes().to_vec();
+        string.extend_from_slice(&[0x55, 0xC4]);
+        string.extend_from_slice(b"HandBrake");
+        let mut file = boxed(b"ftyp", b"qt  \0\0\0\0");
+        file.extend(boxed(b"moov", &boxed(b"udta", &boxed(&ENCODER, &string))));
+        let mut reader = Cursor::new(file);
+        assert_eq!(read_brand(&mut reader).unwrap().as_deref(), Some("qt"));
+        assert_eq!(
+            read_encoder(&mut reader).unwrap().as_deref(),
+            Some("HandBra")
+        );
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let mut keys = vec![0, 0, 0, 0];
+        keys.extend_from_slice(&2u32.to_be_bytes());
+        keys.extend(boxed(b"mdta", b"com.apple.quicktime.make"));
+        keys.extend(boxed(b"mdta", b"com.apple.quicktime.creationdate"));
+        let mut ilst = string_item(2, "2023-04-12T14:39:01+1000");
+        ilst.extend(string_item(1, "Apple"));
+        // An item without a key is ignored
+        ilst.extend(string_item(3, "nope"));
+
+        let mut meta = boxed(b"hdlr", &[0; 24]);
+        meta.extend(boxed(b"keys", &keys));
+        meta.extend(boxed(b"ilst", &ilst));
+        let mut file = boxed(b"ftyp", b"qt  \0\0\0\0");
+        file.extend(boxed(b"moov", &boxed(b"meta", &meta)));
+
+        let items = read_metadata(&mut Cursor::new(file)).unwrap();
+        assert_eq!(
+            items,
+            [
+                (
+                    String::from("com.apple.quicktime.creationdate"),
+                    String::from("2023-04-12T14:39:01+1000")
+                ),
+                (
+                    String::from("com.apple.quicktime.make"),
+                    String::from("Apple")
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,531 @@
+//! Rename, move, copy or link files without replacing existing ones.
+
+use std::ffi::OsString;
+use std::fs::{self, File, FileTimes, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::Error;
+
+/// The most suffixes tried by [`ConflictPolicy::Suffix`] before giving up.
+const MAX_SUFFIX: u32 = 999;
+
+/// What to do when the new name of a file is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Leave the file alone
+    Skip,
+    /// Report an error
+    #[default]
+    Fail,
+    /// Append `-1`, `-2`, etc. to the new name until it is free
+    Suffix,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "fail" => Ok(ConflictPolicy::Fail),
+            "suffix" => Ok(ConflictPolicy::Suffix),
+            _ => Err(format!(
+                "unknown conflict policy '{}', expected skip, fail, or suffix",
+                s
+            )),
+        }
+    }
+}
+
+/// How files are put at their new path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Rename the file, which fails if the new path is on another file system
+    #[default]
+    Rename,
+    /// Rename the file, or copy it and delete the original if the new path is on
+    /// another file system
+    Move,
+    /// Copy the file, keeping its permissions and modification time
+    Copy,
+    /// Make a hard link to the file
+    HardLink,
+    /// Make a copy that shares its data with the original until either changes,
+    /// on file systems that support it (e.g. btrfs and XFS)
+    Reflink,
+}
+
+/// The SHA-256 hashes of a copy and its original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksums {
+    /// The hash of the original, computed as it was copied
+    pub original: [u8; 32],
+    /// The hash of the copy, read back once it was synced to disk
+    pub copy: [u8; 32],
+    /// The size of the file in bytes
+    pub size: u64,
+}
+
+/// Where a file was put, and the hashes checked if it was copied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transferred {
+    pub path: PathBuf,
+    /// Only copies are hashed, and nothing is with a dry run
+    pub checksums: Option<Checksums>,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rename" => Ok(Mode::Rename),
+            "move" => Ok(Mode::Move),
+            "copy" => Ok(Mode::Copy),
+            "hardlink" => Ok(Mode::HardLink),
+            "reflink" => Ok(Mode::Reflink),
+            _ => Err(format!(
+                "unknown mode '{}', expected rename, move, copy, hardlink, or reflink",
+                s
+            )),
+        }
+    }
+}
+
+impl Mode {
+    /// Whether the original file is left where it was.
+    pub fn keeps_original(self) -> bool {
+        matches!(self, Mode::Copy | Mode::HardLink | Mode::Reflink)
+    }
+}
+
+/// Rename `from` to `to`, resolving conflicts with existing files according to
+/// `policy`.
+///
+/// Returns the path the file was (or with `dry_run`, would be) renamed to, or
+/// `None` if it was skipped.
+pub fn rename(
+    from: &Path,
+    to: &Path,
+    policy: ConflictPolicy,
+    dry_run: bool,
+) -> Result<Option<PathBuf>, Error> {
+    let transferred = transfer(from, to, Mode::Rename, policy, dry_run)?;
+    Ok(transferred.map(|transferred| transferred.path))
+}
+
+/// Put the file at `from` at `to` in the way given by `mode`, resolving conflicts
+/// with existing files according to `policy`.
+///
+/// Returns the new path of the file (or with `dry_run`, what it would be), or
+/// `None` if it was skipped.
+pub fn transfer(
+    from: &Path,
+    to: &Path,
+    mode: Mode,
+    policy: ConflictPolicy,
+    dry_run: bool,
+) -> Result<Option<Transferred>, Error> {
+    let mut suffix = 0;
+    loop {
+        let candidate = if suffix == 0 {
+            to.to_path_buf()
+        } else {
+            with_suffix(to, suffix)
+        };
+        let result = if dry_run {
+            match fs::symlink_metadata(&candidate) {
+                Ok(_) => Err(Error::RenameConflict(candidate.clone())),
+                Err(_) => Ok(None),
+            }
+        } else {
+            transfer_noreplace(from, &candidate, mode)
+        };
+
+        match (result, policy) {
+            (Ok(checksums), _) => {
+                return Ok(Some(Transferred {
+                    path: candidate,
+                    checksums,
+                }))
+            }
+            (Err(Error::RenameConflict(_)), ConflictPolicy::Skip) => return Ok(None),
+            (Err(Error::RenameConflict(_)), ConflictPolicy::Suffix) if suffix < MAX_SUFFIX => {
+                suffix += 1
+            }
+            (Err(err), _) => return Err(err),
+        }
+    }
+}
+
+/// Put the file at `from` at `to` in the way given by `mode`, failing with
+/// [`Error::RenameConflict`] if `to` already exists.
+///
+/// Returns the hashes of the file if it was copied.
+pub fn transfer_noreplace(from: &Path, to: &Path, mode: Mode) -> Result<Option<Checksums>, Error> {
+    let copy_error = |err: io::Error| match err.kind() {
+        io::ErrorKind::AlreadyExists => Error::RenameConflict(to.to_path_buf()),
+        _ => Error::Copy(to.to_path_buf(), err),
+    };
+    match mode {
+        Mode::Rename => rename_noreplace(from, to).map(|()| None),
+        Mode::Move => match rename_noreplace(from, to) {
+            Err(Error::Rename(_, err)) if err.raw_os_error() == Some(libc::EXDEV) => {
+                let checksums = copy_noreplace(from, to).map_err(copy_error)?;
+                fs::remove_file(from).map_err(|err| Error::Rename(to.to_path_buf(), err))?;
+                Ok(Some(checksums))
+            }
+            result => result.map(|()| None),
+        },
+        Mode::Copy => copy_noreplace(from, to).map(Some).map_err(copy_error),
+        Mode::HardLink => match fs::hard_link(from, to) {
+            Ok(()) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Err(Error::RenameConflict(to.to_path_buf()))
+            }
+            Err(err) => Err(Error::Link(to.to_path_buf(), err)),
+        },
+        Mode::Reflink => reflink_noreplace(from, to)
+            .map(|()| None)
+            .map_err(copy_error),
+    }
+}
+
+/// Rename `from` to `to`, failing with [`Error::RenameConflict`] if `to` already
+/// exists.
+///
+/// Unlike [`fs::rename`], which silently replaces the destination on Unix, the
+/// check and the rename happen atomically where the OS and file system allow it.
+pub fn rename_noreplace(from: &Path, to: &Path) -> Result<(), Error> {
+    noreplace(from, to).map_err(|err| match err.kind() {
+        io::ErrorKind::AlreadyExists => Error::RenameConflict(to.to_path_buf()),
+        _ => Error::Rename(to.to_path_buf(), err),
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    match renameat2_noreplace(from, to) {
+        // The kernel or file system doesn't support RENAME_NOREPLACE
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+            link_unlink(from, to)
+        }
+        result => result,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    link_unlink(from, to)
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid NUL terminated strings
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Rename by creating a hard link at `to`, which fails if it exists, then
+/// removing `from`.
+fn link_unlink(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::hard_link(from, to) {
+        Ok(()) => fs::remove_file(from),
+        // Some file systems (e.g. FAT on memory cards) don't support hard links,
+        // so fall back on checking before renaming. This is racy, but the best
+        // that can be done there.
+        Err(err)
+            if matches!(
+                err.kind(),
+                io::ErrorKind::Unsupported | io::ErrorKind::PermissionDenied
+            ) =>
+        {
+            if fs::symlink_metadata(to).is_ok() {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            }
+            fs::rename(from, to)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Copy `from` to a new file at `to`, with the same permissions and modification
+/// time, and check that the copy matches once it is on disk.
+///
+/// The original is hashed as it is copied, and the copy is hashed by reading it
+/// back after syncing it, bypassing the page cache where possible. The copy is
+/// removed if anything goes wrong.
+fn copy_noreplace(from: &Path, to: &Path) -> io::Result<Checksums> {
+    let mut source = File::open(from)?;
+    let metadata = source.metadata()?;
+    let mut dest = OpenOptions::new().write(true).create_new(true).open(to)?;
+    let result = (|| {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 1 << 20];
+        let mut size = 0;
+        loop {
+            let len = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            hasher.update(&buf[..len]);
+            dest.write_all(&buf[..len])?;
+            size += len as u64;
+        }
+        dest.set_permissions(metadata.permissions())?;
+        dest.set_times(
+            FileTimes::new()
+                .set_accessed(metadata.accessed()?)
+                .set_modified(metadata.modified()?),
+        )?;
+        dest.sync_all()?;
+        drop_cache(&dest);
+
+        let (copy, copy_size) = hash_file(to)?;
+        let checksums = Checksums {
+            original: hasher.finalize().into(),
+            copy,
+            size,
+        };
+        if checksums.copy != checksums.original || copy_size != size {
+            return Err(io::Error::other("the copy doesn't match the original"));
+        }
+        Ok(checksums)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    result
+}
+
+/// The SHA-256 hash and size of the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<([u8; 32], u64)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut size = 0;
+    loop {
+        let len = match file.read(&mut buf) {
+            Ok(0) => return Ok((hasher.finalize().into(), size)),
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hasher.update(&buf[..len]);
+        size += len as u64;
+    }
+}
+
+/// Format a hash as lowercase hex.
+pub(crate) fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Ask the OS to drop the cached pages of a synced file, so that reading it back
+/// reads what is on the disk.
+#[cfg(target_os = "linux")]
+fn drop_cache(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the file descriptor is open. This is only advice, so failure is
+    // harmless.
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_cache(_file: &File) {}
+
+/// Make a reflink of `from` at `to`.
+#[cfg(target_os = "linux")]
+fn reflink_noreplace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source = File::open(from)?;
+    let metadata = source.metadata()?;
+    let dest = OpenOptions::new().write(true).create_new(true).open(to)?;
+    // SAFETY: both are open file descriptors
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    let result = if ret == 0 {
+        dest.set_permissions(metadata.permissions())
+            .and_then(|()| dest.set_modified(metadata.modified()?))
+    } else {
+        Err(io::Error::last_os_error())
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink_noreplace(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "reflinks are only supported on Linux",
+    ))
+}
+
+/// Set the modification time of the file at `path` to `datetime`, and the access
+/// time too if `access` is set.
+pub fn touch(path: &Path, datetime: OffsetDateTime, access: bool) -> Result<(), Error> {
+    set_times(path, datetime, access).map_err(|err| Error::Touch(path.to_path_buf(), err))
+}
+
+#[cfg(unix)]
+fn set_times(path: &Path, datetime: OffsetDateTime, access: bool) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let time = libc::timespec {
+        tv_sec: datetime.unix_timestamp() as libc::time_t,
+        tv_nsec: datetime.nanosecond() as _,
+    };
+    let omit = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_OMIT,
+    };
+    let times = [if access { time } else { omit }, time];
+    // SAFETY: the path is a valid NUL terminated string, and there are two times,
+    // access then modification, as utimensat expects
+    let ret = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_times(path: &Path, datetime: OffsetDateTime, access: bool) -> io::Result<()> {
+    let mut times = FileTimes::new().set_modified(datetime.into());
+    if access {
+        times = times.set_accessed(datetime.into());
+    }
+    OpenOptions::new().write(true).open(path)?.set_times(times)
+}
+
+/// Append `-<suffix>` to the file stem of `path`.
+pub fn with_suffix(path: &Path, suffix: u32) -> PathBuf {
+    let mut file_name = OsString::from(path.file_stem().unwrap_or_default());
+    file_name.push(format!("-{}", suffix));
+    if let Some(ext) = path.extension() {
+        file_name.push(".");
+        file_name.push(ext);
+    }
+    path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_suffix() {
+        let path = Path::new("folder/1681265941 IMG_0001.MOV");
+        assert_eq!(
+            with_suffix(path, 2),
+            Path::new("folder/1681265941 IMG_0001-2.MOV")
+        );
+        assert_eq!(with_suffix(Path::new("noext"), 1), Path::new("noext-1"));
+    }
+
+    #[test]
+    fn test_transfer() {
+        let dir = std::env::temp_dir().join(format!("mkv-rename-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("IMG_0001.MOV");
+        fs::write(&original, b"video").unwrap();
+
+        let copy = dir.join("copy.MOV");
+        let result = transfer(&original, &copy, Mode::Copy, ConflictPolicy::Fail, false);
+        let checksums = result.unwrap().unwrap().checksums.unwrap();
+        assert_eq!(checksums.original, checksums.copy);
+        assert_eq!(checksums.size, 5);
+        assert_eq!(fs::read(&copy).unwrap(), b"video");
+        assert!(original.exists());
+        assert_eq!(
+            fs::metadata(&copy).unwrap().modified().unwrap(),
+            fs::metadata(&original).unwrap().modified().unwrap()
+        );
+        assert_eq!(hash_file(&copy).unwrap(), (checksums.original, 5));
+        assert_eq!(
+            to_hex(&checksums.copy),
+            "0cab1c9617404faf2b24e221e189ca5945813e14d3f766345b09ca13bbe28ffc"
+        );
+
+        let result = transfer(
+            &original,
+            &copy,
+            Mode::HardLink,
+            ConflictPolicy::Suffix,
+            false,
+        );
+        assert_eq!(
+            result.unwrap(),
+            Some(Transferred {
+                path: dir.join("copy-1.MOV"),
+                checksums: None,
+            })
+        );
+        assert!(matches!(
+            transfer(&original, &copy, Mode::Move, ConflictPolicy::Fail, false),
+            Err(Error::RenameConflict(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_touch() {
+        let dir = std::env::temp_dir().join(format!("mkv-rename-touch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("IMG_0001.MOV");
+        fs::write(&path, b"video").unwrap();
+        let accessed = fs::metadata(&path).unwrap().accessed().unwrap();
+
+        let datetime = time::macros::datetime!(2023-04-12 14:39:01.25 +10);
+        touch(&path, datetime, false).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(OffsetDateTime::from(metadata.modified().unwrap()), datetime);
+        assert_eq!(metadata.accessed().unwrap(), accessed);
+
+        touch(&path, datetime, true).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(OffsetDateTime::from(metadata.accessed().unwrap()), datetime);
+
+        assert!(matches!(
+            touch(&dir.join("missing.MOV"), datetime, false),
+            Err(Error::Touch(_, _))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//! Machine-readable reports of what happened to each file, for other tools to
+//! consume instead of the human-readable output.
+//!
+//! Each file gets one [`Record`], written as a line of JSON or a row of CSV.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::{Container, DateSource};
+
+/// The columns of CSV reports, in order.
+pub const CSV_HEADER: &str =
+    "path,new_path,datetime,source,offset,container,status,error,message,duplicate_of";
+
+/// How to report what happened to each file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable messages
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Comma separated values, with a header row
+    Csv,
+}
+
+/// What happened to a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Renamed,
+    /// The file would have been renamed, but this was a dry run
+    WouldRename,
+    /// The file wasn't renamed, but its times or metadata were set
+    Updated,
+    /// The times or metadata of the file would have been set, but this was a dry
+    /// run
+    WouldUpdate,
+    /// The file was left alone, e.g. because it already has the right name
+    Skipped,
+    Failed,
+}
+
+/// What happened to one file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Record {
+    /// The path of the file as given or found
+    pub path: PathBuf,
+    /// The path the file was renamed to, or would have been
+    pub new_path: Option<PathBuf>,
+    /// The creation date the new name is based on, with the offset applied
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub datetime: Option<OffsetDateTime>,
+    /// Where the creation date came from
+    pub source: Option<DateSource>,
+    /// The offset applied to the creation date, in seconds
+    pub offset: Option<i64>,
+    pub container: Option<Container>,
+    pub status: Status,
+    /// The kind of error, for failed files. See [`crate::Error::kind`].
+    pub error: Option<&'static str>,
+    /// Why the file was skipped or failed
+    pub message: Option<String>,
+    /// The file found with the same contents, for duplicates
+    pub duplicate_of: Option<PathBuf>,
+}
+
+/// Writes records to `writer` in JSON or CSV format.
+pub struct Reporter<W> {
+    format: OutputFormat,
+    writer: W,
+    wrote_header: bool,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "unknown output format '{}', expected text, json, or csv",
+                s
+            )),
+        }
+    }
+}
+
+impl Record {
+    /// A record of a file that failed with `error`.
+    pub fn failed(path: PathBuf, error: &crate::Error) -> Record {
+        Record {
+            path,
+            new_path: None,
+            datetime: None,
+            source: None,
+            offset: None,
+            container: None,
+            status: Status::Failed,
+            error: Some(error.kind()),
+            message: Some(error.to_string()),
+            duplicate_of: None,
+        }
+    }
+
+    /// The record as a row of CSV, without the line ending.
+    pub fn to_csv(&self) -> String {
+        let fields = [
+            Some(self.path.to_string_lossy().into_owned()),
+            self.new_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            self.datetime
+                .map(|datetime| datetime.format(&Rfc3339).unwrap()),
+            self.source.map(serde_name),
+            self.offset.map(|offset| offset.to_string()),
+            self.container.map(serde_name),
+            Some(serde_name(self.status)),
+            self.error.map(String::from),
+            self.message.clone(),
+            self.duplicate_of
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+        ];
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|field| csv_field(field.as_deref().unwrap_or_default()))
+            .collect();
+        fields.join(",")
+    }
+}
+
+impl<W: Write> Reporter<W> {
+    pub fn new(format: OutputFormat, writer: W) -> Self {
+        Reporter {
+            format,
+            writer,
+            wrote_header: false,
+        }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Write `record`, or nothing for the text format.
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Text => return Ok(()),
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                writeln!(self.writer)?;
+            }
+            OutputFormat::Csv => {
+                if !self.wrote_header {
+                    writeln!(self.writer, "{}", CSV_HEADER)?;
+                    self.wrote_header = true;
+                }
+                writeln!(self.writer, "{}", record.to_csv())?;
+            }
+        }
+        self.writer.flush()
+    }
+}
+
+/// The name a value is serialized with, the same in CSV as in JSON.
+fn serde_name<T: Serialize>(value: T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Quote a CSV field if it contains a comma, quote or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_write() {
+        let record = Record {
+            path: PathBuf::from("clips/IMG_4818.mov"),
+            new_path: Some(PathBuf::from("clips/2023-04-12 IMG, \"1\".mov")),
+            datetime: Some(datetime!(2023-04-12 14:39:01 +10)),
+            source: Some(DateSource::QuickTimeCreationDate),
+            offset: Some(0),
+            container: Some(Container::Mp4),
+            status: Status::Renamed,
+            error: None,
+            message: None,
+            duplicate_of: None,
+        };
+        let failed = Record::failed(PathBuf::from("notes.txt"), &crate::Error::UnsupportedFormat);
+
+        let mut reporter = Reporter::new(OutputFormat::Csv, Vec::new());
+        reporter.write(&record).unwrap();
+        reporter.write(&failed).unwrap();
+        assert_eq!(
+            String::from_utf8(reporter.writer).unwrap(),
+            format!(
+                "{}\n{}\n{}\n",
+                CSV_HEADER,
+                "clips/IMG_4818.mov,\"clips/2023-04-12 IMG, \"\"1\"\".mov\",\
+                 2023-04-12T14:39:01+10:00,quicktime-creationdate,0,mp4,renamed,,,",
+                "notes.txt,,,,,,failed,unsupported-format,unknown file type,"
+            )
+        );
+
+        let mut reporter = Reporter::new(OutputFormat::Json, Vec::new());
+        reporter.write(&failed).unwrap();
+        assert_eq!(
+            String::from_utf8(reporter.writer).unwrap(),
+            "{\"path\":\"notes.txt\",\"new_path\":null,\"datetime\":null,\"source\":null,\
+             \"offset\":null,\"container\":null,\"status\":\"failed\",\
+             \"error\":\"unsupported-format\",\"message\":\"unknown file type\",\
+             \"duplicate_of\":null}\n"
+        );
+
+        let mut reporter = Reporter::new(OutputFormat::Text, Vec::new());
+        reporter.write(&record).unwrap();
+        assert!(reporter.writer.is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+//! The kinds of date source, and the order they are tried in.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A kind of place that a creation date can be found.
+///
+/// Each [`DateSource`](crate::DateSource) belongs to one kind. Dates are taken
+/// from the first kind in the priority order that has a plausible date, see
+/// [`CreationDateExtractor::sources`](crate::CreationDateExtractor::sources).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SourceKind {
+    /// Dates in the container metadata: QuickTime tags, the MPEG4 movie header and
+    /// the Matroska segment info
+    Container,
+    /// Dates in the MPEG4 track and media headers
+    Track,
+    /// Embedded EXIF and XMP metadata
+    Exif,
+    /// A date in the file name
+    Filename,
+    /// XMP sidecar files next to the file
+    Sidecar,
+    /// The birth or modification time of the file, which are only a guess at when
+    /// it was recorded
+    Filesystem,
+}
+
+/// The sources tried when no other order is given. The filesystem is left out as
+/// it is only a guess: copying a file often changes its times.
+pub const DEFAULT_SOURCES: [SourceKind; 5] = [
+    SourceKind::Container,
+    SourceKind::Track,
+    SourceKind::Exif,
+    SourceKind::Filename,
+    SourceKind::Sidecar,
+];
+
+/// An ordered list of source kinds, parsed from a comma separated list like
+/// `exif,container,filename`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceOrder(pub Vec<SourceKind>);
+
+impl Default for SourceOrder {
+    fn default() -> Self {
+        SourceOrder(DEFAULT_SOURCES.to_vec())
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "container" => Ok(SourceKind::Container),
+            "track" => Ok(SourceKind::Track),
+            "exif" => Ok(SourceKind::Exif),
+            "filename" => Ok(SourceKind::Filename),
+            "sidecar" => Ok(SourceKind::Sidecar),
+            "filesystem" => Ok(SourceKind::Filesystem),
+            _ => Err(format!(
+                "unknown date source '{}', expected container, track, exif, filename, sidecar, or filesystem",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SourceKind::Container => "container",
+            SourceKind::Track => "track",
+            SourceKind::Exif => "exif",
+            SourceKind::Filename => "filename",
+            SourceKind::Sidecar => "sidecar",
+            SourceKind::Filesystem => "filesystem",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for SourceOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut kinds = Vec::new();
+        for name in s.split(',').map(str::trim) {
+            let kind = name.parse()?;
+            if kinds.contains(&kind) {
+                return Err(format!("date source '{}' given more than once", name));
+            }
+            kinds.push(kind);
+        }
+        Ok(SourceOrder(kinds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source_order() {
+        assert_eq!(
+            "exif, container,filename".parse(),
+            Ok(SourceOrder(vec![
+                SourceKind::Exif,
+                SourceKind::Container,
+                SourceKind::Filename
+            ]))
+        );
+        assert!("exif,exif".parse::<SourceOrder>().is_err());
+        assert!("mtime".parse::<SourceOrder>().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+//! Templates for generating new file names.
+//!
+//! A template is a mix of literal text, [`time` format description][fd]
+//! components for the parts of the creation date, and placeholders in braces:
+//!
+//! | Placeholder   | Value                                                  |
+//! |---------------|--------------------------------------------------------|
+//! | `{timestamp}` | Creation date as a Unix timestamp                      |
+//! | `{name}`      | Original file name                                     |
+//! | `{stem}`      | Original file name without its extension               |
+//! | `{ext}`       | Original file extension                                |
+//! | `{counter}`   | Position among files renamed, `{counter:4}` pads it   |
+//! | `{make}`      | Make of the camera                                     |
+//! | `{model}`     | Model of the camera                                    |
+//! | `{source}`    | Where the creation date came from                      |
+//!
+//! Literal braces are written `{{` and `}}`. For example,
+//! `[year]-[month]-[day]_[hour]-[minute]-[second]_{model}_{stem}.{ext}` produces
+//! names like `2023-04-12_14-39-01_iPhone 13_IMG_4792.mov`.
+//!
+//! [fd]: https://time-rs.github.io/book/api/format-description.html
+
+use std::ffi::OsStr;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use time::format_description::{self, OwnedFormatItem};
+use time::parsing::Parsed;
+use time::OffsetDateTime;
+
+use crate::{DateSource, Error};
+
+/// The template matching the original naming scheme: `<unix timestamp> <original name>`.
+pub const DEFAULT_TEMPLATE: &str = "{timestamp} {name}";
+
+/// The template for directories under a destination: `<year>/<year>-<month>-<day>`.
+pub const DEFAULT_DEST_TEMPLATE: &str = "[year]/[year]-[month]-[day]";
+
+/// A parsed file name template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// Literal text and components of the date
+    Date(OwnedFormatItem),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Timestamp,
+    Name,
+    Stem,
+    Ext,
+    Counter { width: usize },
+    Make,
+    Model,
+    Source,
+}
+
+/// How the current name of a file relates to the name a template gives it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameMatch {
+    /// The file already has the name the template gives it
+    Current,
+    /// The file was named by the template, but with different values, e.g. a
+    /// different creation date. Holds the original name of the file.
+    Stale(String),
+    /// The file was not named by the template
+    Unmatched,
+}
+
+/// The values that placeholders in a template are filled with.
+#[derive(Debug, Clone, Copy)]
+pub struct TemplateContext<'a> {
+    /// The path of the file being renamed
+    pub path: &'a Path,
+    pub datetime: OffsetDateTime,
+    pub source: DateSource,
+    pub counter: u64,
+    pub make: Option<&'a str>,
+    pub model: Option<&'a str>,
+}
+
+impl Template {
+    /// Generate the new path for the file described by `context`.
+    ///
+    /// The new path is in the same directory as the original file.
+    pub fn new_path(&self, context: &TemplateContext<'_>) -> PathBuf {
+        context.path.with_file_name(self.render(context))
+    }
+
+    /// Work out whether the file in `context` was already named with this template.
+    ///
+    /// Each suffix of the file name is tried as the original name. The file was
+    /// named by the template if rendering it with that original name gives the
+    /// current name, or one that only differs where the date, timestamp or
+    /// counter go, and has a valid value of the template's own format there.
+    pub fn match_name(&self, context: &TemplateContext<'_>) -> NameMatch {
+        let name = match context.path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name,
+            None => return NameMatch::Unmatched,
+        };
+
+        for (i, _) in name.char_indices() {
+            let original = &name[i..];
+            let path = context.path.with_file_name(original);
+            let context = TemplateContext {
+                path: &path,
+                ..*context
+            };
+            if self.render(&context) == name {
+                return NameMatch::Current;
+            } else if self.is_stale(&context, name) {
+                return NameMatch::Stale(String::from(original));
+            }
+        }
+        NameMatch::Unmatched
+    }
+
+    /// Render the template into a file name.
+    pub fn render(&self, context: &TemplateContext<'_>) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            render_segment(&mut out, segment, context);
+        }
+        out
+    }
+
+    /// Whether `name` is what the template renders for `context`, but with other
+    /// values that the date, timestamp and counter could have.
+    fn is_stale(&self, context: &TemplateContext<'_>, mut name: &str) -> bool {
+        for segment in &self.segments {
+            let mut rendered = String::new();
+            render_segment(&mut rendered, segment, context);
+            let len = match name.char_indices().nth(rendered.chars().count()) {
+                Some((len, _)) => len,
+                None if name.chars().count() == rendered.chars().count() => name.len(),
+                None => return false,
+            };
+            let (value, rest) = name.split_at(len);
+            let valid = value == rendered
+                || match segment {
+                    Segment::Date(item) => {
+                        let mut parsed = Parsed::new();
+                        parsed.parse_item(value.as_bytes(), item) == Ok(&[][..])
+                    }
+                    Segment::Placeholder(Placeholder::Timestamp) => value.parse::<i64>().is_ok(),
+                    Segment::Placeholder(Placeholder::Counter { .. }) => {
+                        value.bytes().all(|b| b.is_ascii_digit())
+                    }
+                    Segment::Placeholder(_) => false,
+                };
+            if !valid {
+                return false;
+            }
+            name = rest;
+        }
+        name.is_empty()
+    }
+
+    /// Parse a template for directories, where `/` separates them.
+    pub fn parse_dirs(s: &str) -> Result<Template, Error> {
+        Template::parse(s, true)
+    }
+
+    fn parse(s: &str, dirs: bool) -> Result<Template, Error> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| {
+                        Error::InvalidTemplate(String::from("unclosed '{' in template"))
+                    })?;
+                    let placeholder = parse_placeholder(&rest[..end])?;
+                    chars = rest[end + 1..].chars();
+
+                    push_literal(&mut segments, &mut literal)?;
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => {
+                    return Err(Error::InvalidTemplate(String::from(
+                        "unmatched '}' in template, use '}}' for a literal brace",
+                    )))
+                }
+                '\0' => {
+                    return Err(Error::InvalidTemplate(String::from(
+                        "NUL can't be used in a template",
+                    )))
+                }
+                '/' if !dirs => return Err(Error::InvalidTemplate(String::from(
+                    "'/' can't be used in a file name template, use --dest-format for directories",
+                ))),
+                c => literal.push(c),
+            }
+        }
+        push_literal(&mut segments, &mut literal)?;
+
+        Ok(Template { segments })
+    }
+}
+
+/// Render a segment of a template onto the end of `out`.
+fn render_segment(out: &mut String, segment: &Segment, context: &TemplateContext<'_>) {
+    let path = context.path;
+    match segment {
+        Segment::Date(item) => {
+            // Formatting only fails if the value lacks a component, which an
+            // OffsetDateTime never does
+            let formatted = context
+                .datetime
+                .format(item)
+                .expect("unable to format date");
+            out.push_str(&formatted);
+        }
+        Segment::Placeholder(Placeholder::Timestamp) => {
+            write!(out, "{}", context.datetime.unix_timestamp()).unwrap()
+        }
+        Segment::Placeholder(Placeholder::Name) => push_os_str(out, path.file_name()),
+        Segment::Placeholder(Placeholder::Stem) => push_os_str(out, path.file_stem()),
+        Segment::Placeholder(Placeholder::Ext) => push_os_str(out, path.extension()),
+        Segment::Placeholder(Placeholder::Counter { width }) => {
+            write!(out, "{:0width$}", context.counter, width = width).unwrap()
+        }
+        Segment::Placeholder(Placeholder::Make) => push_value(out, context.make),
+        Segment::Placeholder(Placeholder::Model) => push_value(out, context.model),
+        Segment::Placeholder(Placeholder::Source) => write!(out, "{}", context.source).unwrap(),
+    }
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    /// Parse a template for file names, which can't contain `/`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Template::parse(s, false)
+    }
+}
+
+fn parse_placeholder(s: &str) -> Result<Placeholder, Error> {
+    let (name, arg) = match s.split_once(':') {
+        Some((name, arg)) => (name, Some(arg)),
+        None => (s, None),
+    };
+    let placeholder = match (name, arg) {
+        ("timestamp", None) => Placeholder::Timestamp,
+        ("name", None) => Placeholder::Name,
+        ("stem", None) => Placeholder::Stem,
+        ("ext", None) => Placeholder::Ext,
+        ("counter", None) => Placeholder::Counter { width: 0 },
+        ("counter", Some(width)) => Placeholder::Counter {
+            width: width.parse().map_err(|_| {
+                Error::InvalidTemplate(format!("invalid counter width '{}'", width))
+            })?,
+        },
+        ("make", None) => Placeholder::Make,
+        ("model", None) => Placeholder::Model,
+        ("source", None) => Placeholder::Source,
+        _ => {
+            let msg = format!("unknown placeholder {{{}}}", s);
+            return Err(Error::InvalidTemplate(msg));
+        }
+    };
+    Ok(placeholder)
+}
+
+/// Parse the literal text and date components collected so far into a segment.
+fn push_literal(segments: &mut Vec<Segment>, literal: &mut String) -> Result<(), Error> {
+    if !literal.is_empty() {
+        let item = format_description::parse_owned::<2>(literal)
+            .map_err(|err| Error::InvalidTemplate(err.to_string()))?;
+        segments.push(Segment::Date(item));
+        literal.clear();
+    }
+    Ok(())
+}
+
+fn push_os_str(out: &mut String, s: Option<&OsStr>) {
+    if let Some(s) = s {
+        out.push_str(&s.to_string_lossy());
+    }
+}
+
+/// Push a metadata value, replacing characters that can't appear in a file name.
+fn push_value(out: &mut String, value: Option<&str>) {
+    let value = value.unwrap_or("unknown");
+    out.extend(value.chars().map(|c| match c {
+        '/' | '\\' | '\0' => '_',
+        c => c,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(path: &Path) -> TemplateContext<'_> {
+        TemplateContext {
+            path,
+            datetime: OffsetDateTime::from_unix_timestamp(1681310341).unwrap(),
+            source: DateSource::Mp4MovieHeader,
+            counter: 7,
+            make: Some("Apple"),
+            model: Some("iPhone 13"),
+        }
+    }
+
+    #[test]
+    fn test_default_template() {
+        let path = Path::new("folder/IMG_4792.mkv");
+        let new_path = Template::default().new_path(&context(path));
+        assert_eq!(new_path, Path::new("folder/1681310341 IMG_4792.mkv"));
+    }
+
+    #[test]
+    fn test_date_template() {
+        let template: Template =
+            "[year]-[month]-[day]_[hour]-[minute]-[second]_{model}_{stem}_{counter:3}.{ext}"
+                .parse()
+                .unwrap();
+        let path = Path::new("IMG_4792.mov");
+        assert_eq!(
+            template.render(&context(path)),
+            "2023-04-12_14-39-01_iPhone 13_IMG_4792_007.mov"
+        );
+    }
+
+    #[test]
+    fn test_match_name() {
+        let template = Template::default();
+        let matches = |name| template.match_name(&context(Path::new(name)));
+        assert_eq!(matches("1681310341 IMG_4792.mkv"), NameMatch::Current);
+        assert_eq!(
+            matches("1673759524 IMG_4792.mkv"),
+            NameMatch::Stale(String::from("IMG_4792.mkv"))
+        );
+        assert_eq!(matches("IMG_4792.mkv"), NameMatch::Unmatched);
+        assert_eq!(matches("VID_20230412_143901.mp4"), NameMatch::Unmatched);
+    }
+
+    #[test]
+    fn test_match_dropbox_name() {
+        let template: Template = "[year]-[month]-[day] {name}".parse().unwrap();
+        let matches = |name| template.match_name(&context(Path::new(name)));
+        // Dropbox names camera uploads by their date and time
+        assert_eq!(matches("2023-04-12 14.39.01.mov"), NameMatch::Current);
+        assert_eq!(
+            matches("2023-01-15 14.39.01.mov"),
+            NameMatch::Stale(String::from("14.39.01.mov"))
+        );
+        // Digits where the date goes that aren't one are the user's
+        assert_eq!(matches("2023-14-39 01.02.03.mov"), NameMatch::Unmatched);
+        assert_eq!(matches("0000-00-00 notes.mov"), NameMatch::Unmatched);
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!("{nope}".parse::<Template>().is_err());
+        assert!("{name".parse::<Template>().is_err());
+        assert!("name}".parse::<Template>().is_err());
+        assert!("[yaer] {name}".parse::<Template>().is_err());
+        assert!("{{[year]}} {name}".parse::<Template>().is_ok());
+        assert!("[year]/{name}".parse::<Template>().is_err());
+        assert!("{stem}\0.{ext}".parse::<Template>().is_err());
+        assert!(Template::parse_dirs("[year]/[month]").is_ok());
+        assert!(Template::parse_dirs("[year]/\0").is_err());
+    }
+}
@@ -0,0 +1,559 @@
+//! IANA time zones, read from the system zoneinfo database.
+//!
+//! Zones are loaded from the directory named by the `TZDIR` environment variable,
+//! or `/usr/share/zoneinfo`. Both the transitions listed in the TZif file and the
+//! POSIX TZ rule at its end, which covers times after the last transition, are
+//! used.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use time::{Date, Month, OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+const DEFAULT_TZDIR: &str = "/usr/share/zoneinfo";
+
+/// Transitions and rules can't be more than this far apart from the local time
+/// they affect, so only offsets in effect within this many seconds of a local time
+/// need to be considered when resolving it.
+const MAX_OFFSET_SECONDS: i64 = 2 * 24 * 60 * 60;
+
+/// A time zone, e.g. `Europe/Berlin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    name: String,
+    /// Unix timestamps of changes in offset, and the offset from then on, in seconds
+    transitions: Vec<(i64, i32)>,
+    /// The offset before the first transition
+    initial_offset: i32,
+    /// The rule for times after the last transition
+    rule: Option<PosixRule>,
+}
+
+/// The result of resolving a local time in a time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalTime {
+    /// The local time happened exactly once
+    Unique(OffsetDateTime),
+    /// The local time happened twice, as the clocks went back
+    Ambiguous {
+        earlier: OffsetDateTime,
+        later: OffsetDateTime,
+    },
+    /// The local time never happened, as the clocks went forward over it. Holds
+    /// the time the same distance after the change as the local time is after the
+    /// change in the old offset, i.e. moved forward by the length of the gap.
+    Skipped(OffsetDateTime),
+}
+
+/// A POSIX TZ string, like `CET-1CEST,M3.5.0,M10.5.0/3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PosixRule {
+    /// The standard offset from UTC, in seconds east
+    std_offset: i32,
+    dst: Option<DstRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DstRule {
+    /// The daylight saving offset from UTC, in seconds east
+    offset: i32,
+    start: RuleDate,
+    /// Seconds after local midnight (in standard time) that daylight saving starts
+    start_time: i32,
+    end: RuleDate,
+    /// Seconds after local midnight (in daylight saving time) that it ends
+    end_time: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleDate {
+    /// `Jn`: day 1 to 365, never counting February 29
+    Julian(u16),
+    /// `n`: day 0 to 365, counting February 29 in leap years
+    Zero(u16),
+    /// `Mm.w.d`: day `d` (0 is Sunday) of week `w` (5 is the last) of month `m`
+    MonthWeekDay { month: Month, week: u8, weekday: u8 },
+}
+
+impl TimeZone {
+    /// Load the zone called `name` from the zoneinfo database.
+    pub fn load(name: &str) -> io::Result<TimeZone> {
+        let relative = Path::new(name);
+        let valid = !name.is_empty()
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid time zone name",
+            ));
+        }
+        let dir = env::var_os("TZDIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_TZDIR));
+        let data = fs::read(dir.join(relative))?;
+        TimeZone::from_tzif(name, &data)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid TZif file"))
+    }
+
+    /// Parse the contents of a TZif file.
+    pub fn from_tzif(name: &str, data: &[u8]) -> Option<TimeZone> {
+        let header = TzifHeader::parse(data)?;
+        // Version 2 and later files repeat the data with 64-bit times after the
+        // version 1 data, followed by the POSIX TZ rule
+        let (header, block, time_size) = if header.version >= b'2' {
+            let rest = data.get(header.v1_len()..)?;
+            (TzifHeader::parse(rest)?, rest, 8)
+        } else {
+            (header, data, 4)
+        };
+
+        let mut offset = 44;
+        let times = block.get(offset..offset + header.time_count * time_size)?;
+        offset += times.len();
+        let indices = block.get(offset..offset + header.time_count)?;
+        offset += indices.len();
+        let types = block.get(offset..offset + header.type_count * 6)?;
+        offset += types.len();
+        let type_offsets = types
+            .chunks_exact(6)
+            .map(|info| i32::from_be_bytes([info[0], info[1], info[2], info[3]]))
+            .collect::<Vec<_>>();
+
+        let mut transitions = Vec::with_capacity(header.time_count);
+        for (time, &index) in times.chunks_exact(time_size).zip(indices) {
+            let time = match time_size {
+                8 => i64::from_be_bytes(time.try_into().ok()?),
+                _ => i64::from(i32::from_be_bytes(time.try_into().ok()?)),
+            };
+            transitions.push((time, *type_offsets.get(usize::from(index))?));
+        }
+
+        let rule = if time_size == 8 {
+            let footer_start = offset
+                + header.char_count
+                + header.leap_count * 12
+                + header.isstd_count
+                + header.isut_count;
+            block
+                .get(footer_start..)
+                .and_then(|footer| std::str::from_utf8(footer).ok())
+                .and_then(|footer| footer.trim_matches('\n').lines().next())
+                .and_then(PosixRule::parse)
+        } else {
+            None
+        };
+
+        Some(TimeZone {
+            name: String::from(name),
+            transitions,
+            initial_offset: *type_offsets.first()?,
+            rule,
+        })
+    }
+
+    /// The name of the zone, e.g. `Europe/Berlin`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The offset from UTC in effect at `timestamp`, in seconds.
+    fn offset_seconds_at(&self, timestamp: i64) -> i32 {
+        let i = self
+            .transitions
+            .partition_point(|&(time, _)| time <= timestamp);
+        match (i, self.rule) {
+            (i, Some(rule)) if i == self.transitions.len() => rule.offset_at(timestamp),
+            (0, _) => self.initial_offset,
+            (i, _) => self.transitions[i - 1].1,
+        }
+    }
+
+    /// The offset from UTC in effect at `datetime`.
+    pub fn offset_at(&self, datetime: OffsetDateTime) -> UtcOffset {
+        utc_offset(self.offset_seconds_at(datetime.unix_timestamp()))
+    }
+
+    /// Resolve `local`, a time on a clock in this zone, to an actual time.
+    pub fn resolve(&self, local: PrimitiveDateTime) -> LocalTime {
+        let wall = local.assume_utc().unix_timestamp();
+        let before = self.offset_seconds_at(wall - MAX_OFFSET_SECONDS);
+        let after = self.offset_seconds_at(wall + MAX_OFFSET_SECONDS);
+        let candidates = if before == after {
+            vec![before]
+        } else {
+            vec![before, after]
+        };
+
+        // An offset applies if it is the one in effect at the time it gives
+        let mut valid = candidates
+            .iter()
+            .copied()
+            .filter(|&offset| self.offset_seconds_at(wall - i64::from(offset)) == offset)
+            .map(|offset| local.assume_offset(utc_offset(offset)))
+            .collect::<Vec<_>>();
+        valid.sort();
+        match valid.as_slice() {
+            [datetime] => LocalTime::Unique(*datetime),
+            [earlier, later, ..] => LocalTime::Ambiguous {
+                earlier: *earlier,
+                later: *later,
+            },
+            [] => {
+                // In a gap, using the offset from before the gap lands after it
+                let datetime = local.assume_offset(utc_offset(before));
+                LocalTime::Skipped(datetime.to_offset(self.offset_at(datetime)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for TimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+impl LocalTime {
+    /// The actual time, taking the earlier time when the local time is ambiguous.
+    pub fn earliest(&self) -> OffsetDateTime {
+        match *self {
+            LocalTime::Unique(datetime) | LocalTime::Skipped(datetime) => datetime,
+            LocalTime::Ambiguous { earlier, .. } => earlier,
+        }
+    }
+}
+
+fn utc_offset(seconds: i32) -> UtcOffset {
+    // Offsets in the database are always well within the ±25 hours UtcOffset allows
+    UtcOffset::from_whole_seconds(seconds).unwrap_or(UtcOffset::UTC)
+}
+
+struct TzifHeader {
+    version: u8,
+    isut_count: usize,
+    isstd_count: usize,
+    leap_count: usize,
+    time_count: usize,
+    type_count: usize,
+    char_count: usize,
+}
+
+impl TzifHeader {
+    fn parse(data: &[u8]) -> Option<TzifHeader> {
+        if data.get(..4)? != b"TZif" {
+            return None;
+        }
+        let count = |i: usize| -> Option<usize> {
+            let bytes = data.get(20 + i * 4..24 + i * 4)?;
+            usize::try_from(u32::from_be_bytes(bytes.try_into().ok()?)).ok()
+        };
+        Some(TzifHeader {
+            version: *data.get(4)?,
+            isut_count: count(0)?,
+            isstd_count: count(1)?,
+            leap_count: count(2)?,
+            time_count: count(3)?,
+            type_count: count(4)?,
+            char_count: count(5)?,
+        })
+    }
+
+    /// The length of the header and version 1 data block.
+    fn v1_len(&self) -> usize {
+        44 + self.time_count * 5
+            + self.type_count * 6
+            + self.char_count
+            + self.leap_count * 8
+            + self.isstd_count
+            + self.isut_count
+    }
+}
+
+impl PosixRule {
+    fn parse(s: &str) -> Option<PosixRule> {
+        let mut parser = RuleParser(s);
+        parser.name()?;
+        // POSIX offsets are hours west of UTC
+        let std_offset = -parser.time()?;
+        if parser.0.is_empty() {
+            return Some(PosixRule {
+                std_offset,
+                dst: None,
+            });
+        }
+
+        parser.name()?;
+        let offset = if parser.0.starts_with(',') {
+            std_offset.checked_add(3600)?
+        } else {
+            -parser.time()?
+        };
+        parser.expect(',')?;
+        let (start, start_time) = parser.date_time()?;
+        parser.expect(',')?;
+        let (end, end_time) = parser.date_time()?;
+        Some(PosixRule {
+            std_offset,
+            dst: Some(DstRule {
+                offset,
+                start,
+                start_time,
+                end,
+                end_time,
+            }),
+        })
+    }
+
+    fn offset_at(&self, timestamp: i64) -> i32 {
+        let dst = match self.dst {
+            Some(dst) => dst,
+            None => return self.std_offset,
+        };
+        let year = match OffsetDateTime::from_unix_timestamp(timestamp) {
+            Ok(datetime) => datetime.year(),
+            Err(_) => return self.std_offset,
+        };
+        // Check the years either side as well, for rules near the new year
+        for year in [year + 1, year, year - 1] {
+            let start = dst.start.timestamp(year, dst.start_time, self.std_offset);
+            let end = dst.end.timestamp(year, dst.end_time, dst.offset);
+            let (start, end) = match (start, end) {
+                (Some(start), Some(end)) => (start, end),
+                _ => continue,
+            };
+            if start <= end {
+                // Northern hemisphere: daylight saving within the year
+                if (start..end).contains(&timestamp) {
+                    return dst.offset;
+                }
+                if timestamp >= end {
+                    return self.std_offset;
+                }
+            } else {
+                // Southern hemisphere: daylight saving over the new year
+                if timestamp >= start {
+                    return dst.offset;
+                }
+                if timestamp >= end {
+                    return self.std_offset;
+                }
+            }
+        }
+        self.std_offset
+    }
+}
+
+impl RuleDate {
+    /// The Unix timestamp of `time` seconds after midnight on this date in `year`,
+    /// in a zone `offset` seconds east of UTC.
+    fn timestamp(&self, year: i32, time: i32, offset: i32) -> Option<i64> {
+        let date = match *self {
+            RuleDate::Julian(day) => {
+                // February 29 is never counted
+                let ordinal = if time::util::is_leap_year(year) && day >= 60 {
+                    day + 1
+                } else {
+                    day
+                };
+                Date::from_ordinal_date(year, ordinal).ok()?
+            }
+            RuleDate::Zero(day) => Date::from_ordinal_date(year, day + 1).ok()?,
+            RuleDate::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            } => {
+                let first = Date::from_calendar_date(year, month, 1).ok()?;
+                let first_weekday = first.weekday().number_days_from_sunday();
+                let mut day = 1 + (7 + weekday - first_weekday) % 7 + (week - 1) * 7;
+                let days_in_month = month.length(year);
+                while day > days_in_month {
+                    day -= 7;
+                }
+                first.replace_day(day).ok()?
+            }
+        };
+        let midnight = date.midnight().assume_utc().unix_timestamp();
+        Some(midnight + i64::from(time) - i64::from(offset))
+    }
+}
+
+struct RuleParser<'a>(&'a str);
+
+impl RuleParser<'_> {
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.0 = self.0.strip_prefix(c)?;
+        Some(())
+    }
+
+    /// A zone abbreviation, either alphabetic or quoted like `<+10>`.
+    fn name(&mut self) -> Option<&str> {
+        let (name, rest) = if let Some(quoted) = self.0.strip_prefix('<') {
+            let end = quoted.find('>')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = self
+                .0
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(self.0.len());
+            self.0.split_at(end)
+        };
+        self.0 = rest;
+        (!name.is_empty()).then_some(name)
+    }
+
+    /// A signed `hh[:mm[:ss]]` time, in seconds.
+    fn time(&mut self) -> Option<i32> {
+        let sign = match self.0.chars().next()? {
+            '-' => -1,
+            '+' => 1,
+            _ => 0,
+        };
+        if sign != 0 {
+            self.0 = &self.0[1..];
+        }
+        let mut seconds = 0;
+        for (i, multiplier) in [3600, 60, 1].into_iter().enumerate() {
+            if i > 0 && self.expect(':').is_none() {
+                break;
+            }
+            seconds = self
+                .number()?
+                .checked_mul(multiplier)
+                .and_then(|part| part.checked_add(seconds))?;
+        }
+        Some(if sign < 0 { -seconds } else { seconds })
+    }
+
+    fn number(&mut self) -> Option<i32> {
+        let end = self
+            .0
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.0.len());
+        let number = self.0[..end].parse().ok()?;
+        self.0 = &self.0[end..];
+        Some(number)
+    }
+
+    /// A rule date with an optional `/time`, which defaults to 02:00.
+    fn date_time(&mut self) -> Option<(RuleDate, i32)> {
+        let date = if self.expect('J').is_some() {
+            RuleDate::Julian(u16::try_from(self.number()?).ok()?)
+        } else if self.expect('M').is_some() {
+            let month = Month::try_from(u8::try_from(self.number()?).ok()?).ok()?;
+            self.expect('.')?;
+            let week = u8::try_from(self.number()?)
+                .ok()
+                .filter(|w| (1..=5).contains(w))?;
+            self.expect('.')?;
+            let weekday = u8::try_from(self.number()?).ok().filter(|&d| d < 7)?;
+            RuleDate::MonthWeekDay {
+                month,
+                week,
+                weekday,
+            }
+        } else {
+            RuleDate::Zero(u16::try_from(self.number()?).ok()?)
+        };
+        let time = if self.expect('/').is_some() {
+            self.time()?
+        } else {
+            2 * 3600
+        };
+        Some((date, time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{datetime, offset};
+
+    fn berlin() -> TimeZone {
+        // No transitions, so only the rule applies
+        TimeZone {
+            name: String::from("Europe/Berlin"),
+            transitions: Vec::new(),
+            initial_offset: 3600,
+            rule: PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            PosixRule::parse("<+10>-10"),
+            Some(PosixRule {
+                std_offset: 36000,
+                dst: None
+            })
+        );
+        let rule = PosixRule::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        let dst = rule.dst.unwrap();
+        assert_eq!((rule.std_offset, dst.offset), (36000, 39600));
+        assert_eq!(dst.end_time, 3 * 3600);
+        assert_eq!(PosixRule::parse("EST5EDT,M3.2.0").map(|_| ()), None);
+        // Offsets too big to hold
+        assert_eq!(PosixRule::parse("X99999999"), None);
+        assert_eq!(PosixRule::parse("X-596523Y,M3.2.0,M11.1.0"), None);
+    }
+
+    #[test]
+    fn test_offset_at() {
+        let tz = berlin();
+        assert_eq!(tz.offset_at(datetime!(2023-01-15 12:00 UTC)), offset!(+1));
+        assert_eq!(tz.offset_at(datetime!(2023-04-12 12:00 UTC)), offset!(+2));
+        // Summer time starts at 01:00 UTC on the last Sunday in March
+        assert_eq!(tz.offset_at(datetime!(2023-03-26 0:59 UTC)), offset!(+1));
+        assert_eq!(tz.offset_at(datetime!(2023-03-26 1:00 UTC)), offset!(+2));
+
+        let sydney = TimeZone {
+            rule: PosixRule::parse("AEST-10AEDT,M10.1.0,M4.1.0/3"),
+            ..berlin()
+        };
+        assert_eq!(
+            sydney.offset_at(datetime!(2023-01-15 12:00 UTC)),
+            offset!(+11)
+        );
+        assert_eq!(
+            sydney.offset_at(datetime!(2023-06-15 12:00 UTC)),
+            offset!(+10)
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tz = berlin();
+        assert_eq!(
+            tz.resolve(datetime!(2023-04-12 14:39:01)),
+            LocalTime::Unique(datetime!(2023-04-12 14:39:01 +2))
+        );
+        assert_eq!(
+            tz.resolve(datetime!(2023-10-29 2:30)),
+            LocalTime::Ambiguous {
+                earlier: datetime!(2023-10-29 2:30 +2),
+                later: datetime!(2023-10-29 2:30 +1),
+            }
+        );
+        assert_eq!(
+            tz.resolve(datetime!(2023-03-26 2:30)),
+            LocalTime::Skipped(datetime!(2023-03-26 3:30 +2))
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        // Only run where the system has a zoneinfo database
+        if let Ok(tz) = TimeZone::load("Europe/Berlin") {
+            assert_eq!(tz.offset_at(datetime!(1990-07-01 12:00 UTC)), offset!(+2));
+            assert_eq!(tz.offset_at(datetime!(2090-07-01 12:00 UTC)), offset!(+2));
+            assert_eq!(tz.offset_at(datetime!(2090-01-01 12:00 UTC)), offset!(+1));
+        }
+        assert!(TimeZone::load("../etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+//! Find files to process beneath directories.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::Error;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Options controlling which files are found when walking a directory.
+///
+/// Patterns that contain a `/` are matched against the path relative to the
+/// directory being walked, other patterns are matched against the file name.
+/// Matching is case-insensitive, so `*.mov` also matches `IMG_0001.MOV`.
+#[derive(Debug, Default, Clone)]
+pub struct WalkOptions {
+    /// Only files matching at least one of these patterns are returned. All
+    /// files are returned when this is empty.
+    pub include: Vec<Pattern>,
+    /// Files and directories matching any of these patterns are skipped.
+    pub exclude: Vec<Pattern>,
+    /// Descend into symlinked directories.
+    ///
+    /// Symlinks that point back to one of their ancestors are reported as an
+    /// error instead of being followed.
+    pub follow_symlinks: bool,
+}
+
+impl WalkOptions {
+    /// Recursively find the files beneath `root`, in file name order.
+    pub fn walk<'a>(&'a self, root: &'a Path) -> impl Iterator<Item = Result<PathBuf, Error>> + 'a {
+        WalkDir::new(root)
+            .follow_links(self.follow_symlinks)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(move |entry| !self.excluded(root, entry))
+            .filter_map(move |entry| match entry {
+                Ok(entry) => (entry.file_type().is_file() && self.included(root, &entry))
+                    .then(|| Ok(entry.into_path())),
+                Err(err) => Some(Err(Error::Io(io::Error::from(err)))),
+            })
+    }
+
+    fn excluded(&self, root: &Path, entry: &DirEntry) -> bool {
+        // Never exclude the root itself, it was asked for explicitly
+        entry.depth() > 0
+            && self
+                .exclude
+                .iter()
+                .any(|pattern| matches(pattern, root, entry.path()))
+    }
+
+    fn included(&self, root: &Path, entry: &DirEntry) -> bool {
+        self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| matches(pattern, root, entry.path()))
+    }
+}
+
+pub(crate) fn matches(pattern: &Pattern, root: &Path, path: &Path) -> bool {
+    if pattern.as_str().contains('/') {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        pattern.matches_path_with(relative, MATCH_OPTIONS)
+    } else {
+        path.file_name()
+            .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), MATCH_OPTIONS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let root = Path::new("card");
+        let path = Path::new("card/DCIM/100APPLE/IMG_0001.MOV");
+        assert!(matches(&Pattern::new("*.mov").unwrap(), root, path));
+        assert!(matches(&Pattern::new("DCIM/*/*.mov").unwrap(), root, path));
+        assert!(!matches(&Pattern::new("DCIM/*.mov").unwrap(), root, path));
+        assert!(!matches(&Pattern::new("*.mkv").unwrap(), root, path));
+    }
+}
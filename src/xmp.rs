@@ -0,0 +1,160 @@
+//! Finding creation dates in XMP packets, embedded in files or in sidecar files.
+//!
+//! XMP is XML, but the properties of interest are simple values that are written
+//! either as attributes (`exif:DateTimeOriginal="..."`) or as elements
+//! (`<exif:DateTimeOriginal>...</exif:DateTimeOriginal>`), so they are found by
+//! searching the text rather than parsing it.
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use time::format_description::well_known::Iso8601;
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use crate::{bmff, exif, Clock};
+
+/// The signature at the start of the APP1 segment holding XMP in JPEG files.
+const JPEG_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// The extended type of the `uuid` box holding XMP in MPEG4 files.
+const BMFF_UUID: [u8; 16] = [
+    0xBE, 0x7A, 0xCF, 0xCB, 0x97, 0xA9, 0x42, 0xE8, 0x9C, 0x71, 0x99, 0x94, 0x91, 0xE3, 0xAF, 0xAC,
+];
+
+/// XMP packets larger than this are ignored.
+const MAX_PACKET_SIZE: u64 = 1 << 20;
+
+/// The properties holding the creation date, most specific first.
+const DATE_PROPERTIES: [&str; 4] = [
+    "exif:DateTimeOriginal",
+    "photoshop:DateCreated",
+    "xmp:CreateDate",
+    "xmpDM:shotDate",
+];
+
+/// Read the XMP packet embedded in a JPEG file.
+pub(crate) fn read_jpeg<R: Read + Seek>(reader: &mut R) -> io::Result<Option<String>> {
+    match exif::find_app1(reader, JPEG_SIGNATURE)? {
+        Some((start, end)) => read_packet(reader, start, end).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Read the XMP packet embedded in an MPEG4 file, either in a top level `uuid`
+/// box or, as QuickTime writes it, in `moov/udta/XMP_`.
+pub(crate) fn read_bmff<R: Read + Seek>(reader: &mut R) -> io::Result<Option<String>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut offset = 0;
+    while let Some(header) = bmff::read_header(reader, offset, end)? {
+        offset = header.end;
+        if &header.box_type == b"uuid" && header.end - header.start > 16 {
+            let mut uuid = [0; 16];
+            reader.read_exact(&mut uuid)?;
+            if uuid == BMFF_UUID {
+                return read_packet(reader, header.start + 16, header.end).map(Some);
+            }
+        }
+    }
+
+    match bmff::find_path(reader, &[b"moov", b"udta", b"XMP_"])? {
+        Some(header) => read_packet(reader, header.start, header.end).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn read_packet<R: Read + Seek>(reader: &mut R, start: u64, end: u64) -> io::Result<String> {
+    reader.seek(SeekFrom::Start(start))?;
+    let mut packet = Vec::new();
+    reader
+        .take((end - start).min(MAX_PACKET_SIZE))
+        .read_to_end(&mut packet)?;
+    Ok(String::from_utf8_lossy(&packet).into_owned())
+}
+
+/// Find the creation date in an XMP packet.
+///
+/// Dates without an offset are local times, and held as UTC.
+pub(crate) fn creation_date(xmp: &str) -> Option<(OffsetDateTime, Clock)> {
+    DATE_PROPERTIES
+        .iter()
+        .filter_map(|property| property_value(xmp, property))
+        .find_map(parse_date)
+}
+
+/// Read the creation date from the XMP sidecar of the file at `path`, if it has
+/// one. Both `IMG_0001.xmp` and `IMG_0001.MOV.xmp` naming styles are recognised.
+pub(crate) fn sidecar_creation_date(path: &Path) -> Option<(OffsetDateTime, Clock)> {
+    sidecar_paths(path)
+        .into_iter()
+        .filter_map(|path| fs::read(path).ok())
+        .find_map(|xmp| creation_date(&String::from_utf8_lossy(&xmp)))
+}
+
+fn sidecar_paths(path: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for ext in ["xmp", "XMP"] {
+        paths.push(path.with_extension(ext));
+        let mut name = path.as_os_str().to_os_string();
+        name.push(".");
+        name.push(ext);
+        paths.push(PathBuf::from(name));
+    }
+    paths
+}
+
+fn property_value<'a>(xmp: &'a str, property: &str) -> Option<&'a str> {
+    // As an attribute
+    let attribute = format!("{}=", property);
+    if let Some(i) = xmp.find(&attribute) {
+        let rest = &xmp[i + attribute.len()..];
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let rest = &rest[1..];
+        return rest.find(quote).map(|end| &rest[..end]);
+    }
+
+    // As an element
+    let open = format!("<{}>", property);
+    let close = format!("</{}>", property);
+    let start = xmp.find(&open)? + open.len();
+    let end = xmp[start..].find(&close)?;
+    Some(&xmp[start..start + end])
+}
+
+fn parse_date(s: &str) -> Option<(OffsetDateTime, Clock)> {
+    let s = s.trim();
+    OffsetDateTime::parse(s, &Iso8601::DEFAULT)
+        .ok()
+        .map(|datetime| (datetime, Clock::Absolute))
+        .or_else(|| {
+            PrimitiveDateTime::parse(s, &Iso8601::DEFAULT)
+                .ok()
+                .map(|datetime| (datetime.assume_utc(), Clock::Local))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_creation_date() {
+        let attribute = r#"<rdf:Description xmp:CreateDate="2023-04-12T14:39:01+10:00"/>"#;
+        assert_eq!(
+            creation_date(attribute),
+            Some((datetime!(2023-04-12 14:39:01 +10:00), Clock::Absolute))
+        );
+
+        let element = "<rdf:Description>
+            <xmp:CreateDate>2023-04-12T14:39:01</xmp:CreateDate>
+            <exif:DateTimeOriginal>2023-04-12T14:39:00.5</exif:DateTimeOriginal>
+        </rdf:Description>";
+        assert_eq!(
+            creation_date(element),
+            Some((datetime!(2023-04-12 14:39:00.5 UTC), Clock::Local))
+        );
+
+        assert_eq!(creation_date("<xmp:CreateDate>soon</xmp:CreateDate>"), None);
+    }
+}